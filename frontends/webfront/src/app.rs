@@ -16,6 +16,7 @@ use openssl::pkey::{self, PKey};
 use lmdb::{Transaction};
 
 use snapfaas::blobstore::Blobstore;
+use snapfaas::codec::Codec;
 use snapfaas::request;
 
 struct SnapFaasManager {
@@ -23,25 +24,25 @@ struct SnapFaasManager {
 }
 
 impl r2d2::ManageConnection for SnapFaasManager {
-    type Connection = TcpStream;
+    type Connection = Codec<TcpStream>;
     type Error = std::io::Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        Ok(TcpStream::connect(&self.address)?)
+        Codec::connect(&self.address)
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
         let req = request::Request {
             function: String::from("ping"),
             payload: serde_json::Value::Null,
+            ..Default::default()
         };
-        request::write_u8(&req.to_vec(), conn)?;
-        request::read_u8(conn)?;
+        conn.invoke(&req)?;
         Ok(())
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.take_error().ok().flatten().is_some()
+        conn.get_ref().take_error().ok().flatten().is_some()
     }
 }
 
@@ -256,20 +257,15 @@ impl App {
                 "course": input_json.course,
                 "gh_handles": gh_handles,
             }),
+            ..Default::default()
         };
-        request::write_u8(&req.to_vec(), conn).map_err(|_|
+        let rsp = conn.invoke(&req).map_err(|_|
             Response::json(&serde_json::json!({
-                "error": "failed to send request"
+                "error": "failed to invoke function"
             })).with_status_code(500))?;
-
-        let resp_buf = request::read_u8(conn).map_err(|_|
-            Response::json(&serde_json::json!({
-                "error": "failed to read response"
-            })).with_status_code(500))?;
-        let rsp: request::Response = serde_json::from_slice(&resp_buf).unwrap();
         match rsp.status {
-            request::RequestStatus::SentToVM(response) => Ok(Response::text(response)),
-            _ => Err(Response::json(&serde_json::json!({"error": format!("{:?}", rsp.status)}))),
+            request::RequestStatus::SentToVM => Ok(Response::json(&rsp.output)),
+            _ => Err(Response::json(&serde_json::json!({"error": format!("{:?}", rsp.status), "cause": rsp.error}))),
         }
     }
 
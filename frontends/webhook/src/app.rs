@@ -5,6 +5,7 @@ use log::{error, debug};
 
 use std::net::TcpStream;
 
+use snapfaas::codec::Codec;
 use snapfaas::request;
 
 use httpserver::Handler;
@@ -14,25 +15,25 @@ struct SnapFaasManager {
 }
 
 impl r2d2::ManageConnection for SnapFaasManager {
-    type Connection = TcpStream;
+    type Connection = Codec<TcpStream>;
     type Error = std::io::Error;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        Ok(TcpStream::connect(&self.address)?)
+        Codec::connect(&self.address)
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
         let req = request::Request {
             function: String::from("ping"),
             payload: serde_json::Value::Null,
+            ..Default::default()
         };
-        request::write_u8(&req.to_vec(), conn)?;
-        request::read_u8(conn)?;
+        conn.invoke(&req)?;
         Ok(())
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.take_error().ok().flatten().is_some()
+        conn.get_ref().take_error().ok().flatten().is_some()
     }
 }
 
@@ -90,30 +91,32 @@ impl App {
                     serde_json::from_slice(request.body().as_ref()).or(Err(StatusCode::BAD_REQUEST))?;
                 event_body.insert(String::from("event"), etype.into());
 
+                // GitHub expects an answer within seconds, so don't wait for the function
                 let req = request::Request {
                     function: "gh_repo".to_string(),
                     payload: event_body.into(),
+                    is_async: true,
+                    ..Default::default()
                 };
 
                 let conn = &mut self.conn.get().expect("Lock failed");
-                if let Err(e) = request::write_u8(&req.to_vec(), conn) {
-                    error!("Failed to send request to snapfaas: {:?}", e);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
-
-                match request::read_u8(conn) {
+                match conn.invoke(&req) {
                     Err(e) => {
-                        error!("Failed to read response from snapfaas: {:?}", e);
+                        error!("Failed to invoke snapfaas: {:?}", e);
                         Err(StatusCode::INTERNAL_SERVER_ERROR)
                     },
-                    Ok(buf) => {
-                        let rsp: request::Response = serde_json::from_slice(&buf).unwrap();
+                    Ok(rsp) => {
                         debug!("Reponse {:?}", rsp);
                         match rsp.status {
-                            request::RequestStatus::ResourceExhausted => Err(StatusCode::TOO_MANY_REQUESTS),
-                            request::RequestStatus::FunctionNotExist | request::RequestStatus::Dropped => Err(StatusCode::BAD_REQUEST),
-                            request::RequestStatus::LaunchFailed => Err(StatusCode::INTERNAL_SERVER_ERROR),
-                            request::RequestStatus::SentToVM(response) => Ok(Bytes::from(response)),
+                            request::RequestStatus::ResourceExhausted |
+                            request::RequestStatus::Overloaded => Err(StatusCode::TOO_MANY_REQUESTS),
+                            request::RequestStatus::Timeout => Err(StatusCode::GATEWAY_TIMEOUT),
+                            request::RequestStatus::FunctionNotExist | request::RequestStatus::Dropped |
+                            request::RequestStatus::BadRequest(_) => Err(StatusCode::BAD_REQUEST),
+                            request::RequestStatus::LaunchFailed | request::RequestStatus::ExecutionFailed |
+                            request::RequestStatus::InvokeRejected => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                            request::RequestStatus::SentToVM => Ok(Bytes::from(rsp.output.to_string())),
+                            request::RequestStatus::Accepted(id) => Ok(Bytes::from(id)),
                         }
                     },
                 }
@@ -1,6 +1,6 @@
 //! The SnapFaaS Controller
 //!
-//! The Controller consists of a request manager (file or HTTP) and a pool of workers.
+//! The Controller consists of a gateway (file, TCP or HTTP) and a pool of workers.
 //! The gateway takes in requests. The controller assigns each request a worker.
 //! Each worker is responsible for finding a VM to handle the request and proxies the response.
 //!
@@ -10,16 +10,24 @@
 //!   3. function store and their files' locations
 
 use clap::{App, Arg};
-use log::{warn};
+use log::{error, info, warn};
+use snapfaas::cgroup;
+use snapfaas::codec;
 use snapfaas::configs;
 use snapfaas::resource_manager::ResourceManager;
 use snapfaas::gateway;
-use snapfaas::message::Message;
-use snapfaas::worker::Worker;
+use snapfaas::message::{Message, RequestInfo};
+use snapfaas::prewarm::{self, Prewarmer};
+use snapfaas::queue::{self, RequestQueue};
+use snapfaas::registry::Registry;
+use snapfaas::results;
+use snapfaas::worker::{self, Worker};
+use snapfaas::workflow::Engine;
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 fn main() {
     env_logger::init();
@@ -43,9 +51,95 @@ fn main() {
                 .long("listen")
                 .short("l")
                 .takes_value(true)
-                .required(true)
+                .required_unless("requests file")
+                .conflicts_with("requests file")
                 .help("Address on which SnapFaaS listen for connections that sends requests"),
         )
+        .arg(
+            Arg::with_name("requests file")
+                .value_name("FILE")
+                .long("requests_file")
+                .takes_value(true)
+                .help("Replay requests from a JSON Lines file instead of listening for connections"),
+        )
+        .arg(
+            Arg::with_name("exit after replay")
+                .long("exit_after_replay")
+                .requires("requests file")
+                .help("Shut down once every request in the requests file has been responded to"),
+        )
+        .arg(
+            Arg::with_name("gateway")
+                .value_name("TYPE")
+                .long("gateway")
+                .short("g")
+                .takes_value(true)
+                .possible_values(&["tcp", "http"])
+                .default_value("tcp")
+                .help("Protocol spoken on the listen address: length-prefixed JSON (tcp) or HTTP/1.1 (http)"),
+        )
+        .arg(
+            Arg::with_name("max frame size")
+                .value_name("BYTES")
+                .long("max_frame_size")
+                .takes_value(true)
+                .help("Largest request accepted from a client, 16 MiB by default"),
+        )
+        .arg(
+            Arg::with_name("queue depth")
+                .value_name("N")
+                .long("queue_depth")
+                .takes_value(true)
+                .help("Maximum number of requests waiting for a worker, 1024 by default"),
+        )
+        .arg(
+            Arg::with_name("starvation limit")
+                .value_name("MS")
+                .long("starvation_limit")
+                .takes_value(true)
+                .help("Queueing time after which a request is served regardless of its priority, 1000 by default"),
+        )
+        .arg(
+            Arg::with_name("max invoke depth")
+                .value_name("N")
+                .long("max_invoke_depth")
+                .takes_value(true)
+                .help("Maximum number of nested invocations below a request from a client, 8 by default"),
+        )
+        .arg(
+            Arg::with_name("allow invoke cycles")
+                .long("allow_invoke_cycles")
+                .help("Let a function invoke a function that is already on its chain of invocations"),
+        )
+        .arg(
+            Arg::with_name("result ttl")
+                .value_name("SECS")
+                .long("result_ttl")
+                .takes_value(true)
+                .help("Time after which results of asynchronous requests are removed, 86400 by default"),
+        )
+        .arg(
+            Arg::with_name("registry")
+                .value_name("FILE")
+                .long("registry")
+                .takes_value(true)
+                .help("Let HTTP clients deploy functions and persist them to FILE, requires --gateway http"),
+        )
+        .arg(
+            Arg::with_name("cpu overcommit")
+                .value_name("RATIO")
+                .long("cpu_overcommit")
+                .takes_value(true)
+                .help("Limit the vCPUs of all VMs to RATIO times the CPUs, unlimited by default"),
+        )
+        .arg(
+            Arg::with_name("cpus")
+                .value_name("N")
+                .long("cpus")
+                .takes_value(true)
+                .requires("cpu overcommit")
+                .help("Number of CPUs available to VMs, all CPUs of the machine by default"),
+        )
         .arg(Arg::with_name("total memory")
                 .value_name("MB")
                 .long("mem")
@@ -55,67 +149,196 @@ fn main() {
         )
         .get_matches();
 
+    // only the HTTP gateway serves the endpoints that deploy functions
+    if matches.is_present("registry") && (!matches.is_present("listen address") || matches.value_of("gateway") != Some("http")) {
+        clap::Error::with_description(
+            "--registry requires --listen with --gateway http, the only gateway that deploys functions",
+            clap::ErrorKind::ArgumentConflict,
+        ).exit();
+    }
+
     // populate the in-memory config struct
     let config_path = matches.value_of("config").unwrap();
     let config = configs::ResourceManagerConfig::new(config_path);
+    if let Some(cgroups) = config.cgroups.as_ref() {
+        cgroup::init(cgroups, config.functions.keys()).expect("Failed to set up cgroups");
+    }
+
+    // create the queue of requests to the worker pool
+    let queue_depth = matches.value_of("queue depth")
+        .map_or(queue::DEFAULT_MAX_DEPTH, |s| s.parse::<usize>().expect("Queue depth is not a valid integer"));
+    let starvation_limit = matches.value_of("starvation limit")
+        .map_or(queue::DEFAULT_STARVATION_LIMIT, |s| Duration::from_millis(
+            s.parse::<u64>().expect("Starvation limit is not a valid integer")));
+    let request_queue = RequestQueue::new(queue_depth, starvation_limit, &config.functions);
+    request_queue.set_aliases(&config.aliases);
+    let max_invoke_depth = matches.value_of("max invoke depth")
+        .map_or(queue::DEFAULT_MAX_INVOKE_DEPTH, |s| s.parse::<usize>().expect("Max invoke depth is not a valid integer"));
+    request_queue.set_invoke_limits(queue::InvokeLimits {
+        max_depth: max_invoke_depth,
+        allow_cycles: matches.is_present("allow invoke cycles"),
+    });
+    let workflows = Engine::new(config.workflows.clone(), request_queue.clone());
 
     // create the resource manager
-    let (mut manager, manager_sender) = ResourceManager::new(config);
+    let (mut manager, manager_sender) = ResourceManager::new(config.clone());
+    // register the functions deployed at runtime
+    let registry = matches.value_of("registry").map(|path| {
+        Registry::open(path, config, request_queue.clone(), manager_sender.clone())
+            .expect("Failed to open the function registry")
+    });
 
     // set total memory
     let total_mem = matches.value_of("total memory").unwrap()
         .parse::<usize>().expect("Total memory is not a valid integer");
     manager.set_total_mem(total_mem);
 
+    // set vCPU limit
+    if let Some(ratio) = matches.value_of("cpu overcommit") {
+        let ratio = ratio.parse::<f64>().expect("CPU overcommit ratio is not a valid number");
+        let cpus = matches.value_of("cpus")
+            .map(|s| s.parse::<usize>().expect("Number of CPUs is not a valid integer"))
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        manager.set_cpu_overcommit(cpus, ratio);
+    }
+
+    // expire results of asynchronous requests that clients never remove
+    let result_ttl = matches.value_of("result ttl")
+        .map_or(results::DEFAULT_TTL, |s| Duration::from_secs(s.parse::<u64>().expect("Result TTL is not a valid integer")));
+    results::start_sweeper(result_ttl);
+
     // create the worker pool
-    let (pool, request_sender) = new_workerpool(manager.total_mem()/128, manager_sender.clone());
+    let pool = new_workerpool(manager.total_mem()/128, manager_sender.clone(), &request_queue);
     // kick off the resource manager
     let manager_handle = manager.run();
+    // launch VMs ahead of demand, on a cid after the workers'
+    let prewarmer = Prewarmer::new(request_queue.clone(), manager_sender.clone(), pool.len() as u32 + 100, prewarm::DEFAULT_INTERVAL);
 
     // register signal handler
-    set_ctrlc_handler(request_sender.clone(), pool, manager_sender, Some(manager_handle));
+    let prewarmer = Arc::new(Mutex::new(Some(prewarmer)));
+    let pool = Arc::new(Mutex::new(pool));
+    let manager_handle = Arc::new(Mutex::new(Some(manager_handle)));
+    set_ctrlc_handler(request_queue.clone(), prewarmer.clone(), pool.clone(), manager_sender.clone(), manager_handle.clone());
+    set_sighup_handler(config_path.to_string(), request_queue.clone(), workflows.clone(), manager_sender.clone(), registry.clone());
 
-    // TCP gateway
     if let Some(l) = matches.value_of("listen address") {
-        let gateway = gateway::HTTPGateway::listen(l);
-
-        for (request, response_tx, timestamps) in gateway {
-            // Return when a VM acquisition succeeds or fails
-            // but before a VM launches (if it is newly allocated)
-            // and execute the request.
-            request_sender.send(Message::Request((request, response_tx, timestamps))).expect("Failed to send request");
+        let max_frame_size = matches.value_of("max frame size")
+            .map_or(codec::DEFAULT_MAX_FRAME_SIZE, |s| s.parse::<usize>().expect("Max frame size is not a valid integer"));
+        match matches.value_of("gateway").unwrap() {
+            "http" => match registry {
+                Some(registry) => forward_requests(gateway::HTTPGateway::with_registry(l, max_frame_size, registry), &workflows),
+                None => forward_requests(gateway::HTTPGateway::new(l, max_frame_size), &workflows),
+            },
+            _ => forward_requests(gateway::TCPGateway::new(l, max_frame_size), &workflows),
         }
+    } else if let Some(f) = matches.value_of("requests file") {
+        let exit_after_replay = matches.is_present("exit after replay");
+        forward_requests(gateway::FileGateway::replay(f, exit_after_replay), &workflows);
+        // the file gateway only stops after all responses are in
+        shutdown(&request_queue, &prewarmer, &pool, &manager_sender, &manager_handle);
     }
 }
 
-fn new_workerpool(pool_size: usize, manager_sender: Sender<Message>) -> (Vec<Worker>, Sender<Message>) {
-    let (request_sender, response_receiver) = mpsc::channel();
-    let response_receiver = Arc::new(Mutex::new(response_receiver));
+fn forward_requests<G: Iterator<Item = RequestInfo>>(gateway: G, workflows: &Engine) {
+    for request_info in gateway {
+        // Return right away, a request that does not fit in the queue
+        // is responded to with an overload status. Requests naming a
+        // workflow are run by the workflow engine.
+        workflows.submit(request_info);
+    }
+}
 
+fn new_workerpool(pool_size: usize, manager_sender: Sender<Message>, request_queue: &RequestQueue) -> Vec<Worker> {
     let mut pool = Vec::with_capacity(pool_size);
+    // callers blocked in synchronous invocations must not leave their callees without a worker
+    let reserved = worker::reserved_workers(pool_size);
 
     for i in 0..pool_size {
         let cid = i as u32 + 100;
-        pool.push(Worker::new(response_receiver.clone(), manager_sender.clone(), request_sender.clone(), cid));
+        if i < reserved {
+            pool.push(Worker::reserved(request_queue.clone(), manager_sender.clone(), cid));
+        } else {
+            pool.push(Worker::new(request_queue.clone(), manager_sender.clone(), cid));
+        }
     }
 
-    (pool, request_sender)
+    pool
 }
 
-fn set_ctrlc_handler(request_sender: Sender<Message>, mut pool: Vec<Worker>, manager_sender: Sender<Message>, mut manager_handle: Option<JoinHandle<()>>) {
+fn set_ctrlc_handler(
+    request_queue: RequestQueue,
+    prewarmer: Arc<Mutex<Option<Prewarmer>>>,
+    pool: Arc<Mutex<Vec<Worker>>>,
+    manager_sender: Sender<Message>,
+    manager_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+) {
     ctrlc::set_handler(move || { 
         println!("");
         warn!("{}", "Handling Ctrl-C. Shutting down...");
-        let pool_size = pool.len();
-        for _ in 0..pool_size {
-            request_sender.send(Message::Shutdown).expect("failed to shut down workers");
-        }
-        while let Some(worker) = pool.pop() {
-            worker.join().expect("failed to join worker thread");
-        }
-        snapfaas::unlink_unix_sockets();
-        manager_sender.send(Message::Shutdown).expect("failed to shut down resource manager");
-        manager_handle.take().map(JoinHandle::join).unwrap().expect("failed to join resource manager thread");
+        shutdown(&request_queue, &prewarmer, &pool, &manager_sender, &manager_handle);
         std::process::exit(0);
     }).expect("Error setting Ctrl-C handler");
 }
+
+// Reload the configuration file on SIGHUP. A file that is not a valid configuration is ignored.
+// Functions deployed to the registry, if any, stay registered.
+fn set_sighup_handler(
+    config_path: String,
+    request_queue: RequestQueue,
+    workflows: Engine,
+    manager_sender: Sender<Message>,
+    registry: Option<Registry>,
+) {
+    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGHUP])
+        .expect("Error setting SIGHUP handler");
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let config = match configs::ResourceManagerConfig::load(&config_path) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Failed to reload {:?}, keeping the current configuration: {}", config_path, e);
+                    continue;
+                }
+            };
+            info!("Reloading {:?}", config_path);
+            if let Some(cgroups) = config.cgroups.as_ref() {
+                if let Err(e) = cgroup::init(cgroups, config.functions.keys()) {
+                    error!("Failed to set up cgroups: {:?}", e);
+                }
+            }
+            workflows.set_workflows(config.workflows.clone());
+            request_queue.set_aliases(&config.aliases);
+            if let Some(registry) = registry.as_ref() {
+                registry.reload(config);
+                continue;
+            }
+            request_queue.set_functions(&config.functions);
+            if manager_sender.send(Message::Reload(config)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn shutdown(
+    request_queue: &RequestQueue,
+    prewarmer: &Mutex<Option<Prewarmer>>,
+    pool: &Mutex<Vec<Worker>>,
+    manager_sender: &Sender<Message>,
+    manager_handle: &Mutex<Option<JoinHandle<()>>>,
+) {
+    // stop launching VMs before the workers and the resource manager go away
+    if let Some(prewarmer) = prewarmer.lock().unwrap().take() {
+        prewarmer.shutdown().expect("failed to join pre-warmer thread");
+    }
+    let mut pool = pool.lock().unwrap();
+    request_queue.shutdown(pool.len());
+    while let Some(worker) = pool.pop() {
+        worker.join().expect("failed to join worker thread");
+    }
+    snapfaas::unlink_unix_sockets();
+    if let Some(handle) = manager_handle.lock().unwrap().take() {
+        manager_sender.send(Message::Shutdown).expect("failed to shut down resource manager");
+        handle.join().expect("failed to join resource manager thread");
+    }
+}
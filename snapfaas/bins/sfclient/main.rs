@@ -2,7 +2,7 @@
 extern crate clap;
 use clap::{App, Arg};
 use snapfaas::request;
-use std::net::TcpStream;
+use snapfaas::codec::Codec;
 use std::io::{Read, stdin};
 
 fn main() -> std::io::Result<()> {
@@ -28,6 +28,11 @@ fn main() -> std::io::Result<()> {
                 .required(true)
                 .help("Function name"),
         )
+        .arg(
+            Arg::with_name("async")
+                .long("async")
+                .help("Return the request's ID right away instead of waiting for the response"),
+        )
         .get_matches();
 
 
@@ -39,12 +44,12 @@ fn main() -> std::io::Result<()> {
     let request = request::Request {
         function,
         payload,
+        is_async: cmd_arguments.is_present("async"),
+        ..Default::default()
     };
 
-    let mut connection = TcpStream::connect(addr)?;
-    request::write_u8(&request.to_vec(), &mut connection)?;
-    input = request::read_u8(&mut connection)?;
-    let response: request::Response = serde_json::from_slice(&input)?;
+    let mut connection = Codec::connect(addr)?;
+    let response = connection.invoke(&request)?;
     println!("{:?}", response);
     Ok(())
 }
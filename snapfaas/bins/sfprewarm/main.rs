@@ -0,0 +1,130 @@
+//! Offline evaluation of predictive pre-warming
+//!
+//! Replays the requests recorded by `multivm` workers in `out/thread-*.stat` against the
+//! predictive policy (see `snapfaas::prediction`) and against a fixed keep-alive, and reports,
+//! for each function, the cold starts and the time a VM is kept warm but idle under both.
+//! Each function is assumed to run on a single VM.
+use clap::{App, Arg};
+use serde::Deserialize;
+
+use snapfaas::metrics::RequestTimestamps;
+use snapfaas::prediction::{self, Evaluation, PredictionConfig};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+// the part of the controller YAML the policy is configured by
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    prediction: Option<PredictionConfig>,
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("SnapFaaS pre-warming evaluation")
+        .version("1.0")
+        .about("Replay recorded requests against the predictive pre-warming policy")
+        .arg(
+            Arg::with_name("config")
+                .value_name("YAML")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .required(false)
+                .help("Controller config YAML file whose `prediction` section configures the policy"),
+        )
+        .arg(
+            Arg::with_name("keep_alive")
+                .value_name("MS")
+                .long("keep_alive")
+                .takes_value(true)
+                .required(false)
+                .default_value("600000")
+                .help("Keep-alive in ms of the fixed policy compared against"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .value_name("FILE")
+                .multiple(true)
+                .required(false)
+                .help("Stat files to replay, defaults to out/thread-*.stat"),
+        )
+        .get_matches();
+
+    let config = matches.value_of("config")
+        .map(|path| {
+            let file = File::open(path).expect("Failed to open the config file");
+            serde_yaml::from_reader::<_, Config>(file).expect("Invalid YAML file")
+        })
+        .unwrap_or_default()
+        .prediction
+        .unwrap_or_default();
+    let keep_alive = matches.value_of("keep_alive").unwrap().parse::<u64>()
+        .map(Duration::from_millis)
+        .expect("keep_alive is not a valid number");
+    let paths: Vec<String> = match matches.values_of("stats") {
+        Some(paths) => paths.map(String::from).collect(),
+        None => glob::glob("out/thread-*.stat")
+            .expect("Invalid glob pattern")
+            .filter_map(Result::ok)
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect(),
+    };
+
+    // arrival times of each function's requests in Unix time, the clock the resource manager
+    // predicts arrivals with, so that the busy hours of the day are the same
+    let mut arrivals = BTreeMap::<String, Vec<Duration>>::new();
+    let mut without_offset = 0;
+    for path in &paths {
+        let file = File::open(path).unwrap_or_else(|e| panic!("Failed to open {:?}: {:?}", path, e));
+        for line in BufReader::new(file).lines() {
+            let line = line.expect("Failed to read stat file");
+            let record: RequestTimestamps = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Skipping a malformed record in {:?}: {:?}", path, e);
+                    continue;
+                }
+            };
+            let at = [record.at_gateway, record.at_vmm, record.arrived].iter().copied().find(|&at| at != 0);
+            if let Some(at) = at {
+                if record.unix_offset == 0 {
+                    without_offset += 1;
+                }
+                arrivals.entry(record.request.function).or_default().push(Duration::from_nanos(at + record.unix_offset));
+            }
+        }
+    }
+    if without_offset > 0 {
+        log::warn!("{} records do not say how to convert their timestamps to Unix time, \
+            their hours of the day are wrong", without_offset);
+    }
+    if arrivals.is_empty() {
+        eprintln!("No requests recorded in {:?}", paths);
+        std::process::exit(1);
+    }
+
+    println!("{:<24} {:>10} {:>14} {:>14} {:>16} {:>16}",
+        "function", "requests", "cold(predict)", "cold(fixed)", "idle s(predict)", "idle s(fixed)");
+    let mut total = (Evaluation::default(), Evaluation::default());
+    for (function, mut arrivals) in arrivals {
+        arrivals.sort();
+        let predictive = prediction::evaluate(&arrivals, &config);
+        let fixed = prediction::evaluate_fixed(&arrivals, keep_alive);
+        println!("{:<24} {:>10} {:>14} {:>14} {:>16.1} {:>16.1}",
+            function, predictive.requests, predictive.cold_starts, fixed.cold_starts,
+            predictive.warm_time.as_secs_f64(), fixed.warm_time.as_secs_f64());
+        for (total, evaluation) in [(&mut total.0, predictive), (&mut total.1, fixed)] {
+            total.requests += evaluation.requests;
+            total.cold_starts += evaluation.cold_starts;
+            total.warm_time += evaluation.warm_time;
+        }
+    }
+    println!("{:<24} {:>10} {:>14} {:>14} {:>16.1} {:>16.1}",
+        "total", total.0.requests, total.0.cold_starts, total.1.cold_starts,
+        total.0.warm_time.as_secs_f64(), total.1.warm_time.as_secs_f64());
+}
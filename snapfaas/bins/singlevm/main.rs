@@ -201,6 +201,13 @@ fn main() {
         memory: cmd_arguments.value_of("mem_size").expect("mem_size")
                             .parse::<usize>().expect("mem_size not int"),
         concurrency_limit: 1,
+        reserved_concurrency: 0,
+        min_idle: 0,
+        max_idle: None,
+        keep_alive: None,
+        queue_limit: None,
+        priority: Default::default(),
+        tenant: None,
         load_dir: cmd_arguments.value_of("load_dir").map(|s| s.to_string()),
         dump_dir: cmd_arguments.value_of("dump_dir").map(|s| s.to_string()),
         copy_base: cmd_arguments.is_present("copy_base_memory"),
@@ -209,6 +216,7 @@ fn main() {
         cmdline: cmd_arguments.value_of("kernel_args").map(|s| s.to_string()),
         dump_ws: cmd_arguments.is_present("dump working set"),
         load_ws: cmd_arguments.is_present("load working set"),
+        timeout: None,
     };
     let id = cmd_arguments.value_of("id").unwrap().parse::<usize>().unwrap();
     let odirect = snapfaas::vm::OdirectOption {
@@ -259,7 +267,7 @@ fn main() {
     for req in requests {
         let t1 = Instant::now();
         log::debug!("request: {:?}", req);
-        match vm.process_req(req) {
+        match vm.process_req(req, None) {
             Ok(rsp) => {
                 let t2 = Instant::now();
                 println!("request returned in: {} us", t2.duration_since(t1).as_micros());
@@ -0,0 +1,94 @@
+//! Versions of functions and aliases that split traffic between them
+//!
+//! A version of a function is a function of its own named `NAME@VERSION`, e.g. `grader@v2`,
+//! with its own `FunctionConfig`, idle VMs and metrics. An alias, declared under `aliases` in
+//! the controller YAML, routes each request to one of several functions chosen at random in
+//! proportion to their weights:
+//!
+//! ```yaml
+//! aliases:
+//!   grader@stable:
+//!     grader@v1: 1
+//!   grader@canary:
+//!     grader@v1: 90
+//!     grader@v2: 10
+//!   # requests to `grader` go to the stable version
+//!   grader:
+//!     grader@v1: 1
+//! ```
+//!
+//! The request queue routes requests to aliases as they are submitted, so requests from
+//! clients, from functions through the invoke syscall and from workflows alike. A routed request
+//! names the version it was routed to and keeps the alias in `Request::alias`, both of which are
+//! recorded in `out/thread-*.stat`. Canarying a new build and rolling it back are edits of the
+//! weights followed by a reload of the configuration.
+use std::collections::BTreeMap;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Alias {
+    /// from the function to route to to its weight
+    pub weights: BTreeMap<String, u32>,
+}
+
+impl Alias {
+    /// Check that the alias routes somewhere
+    pub fn validate(&self) -> Result<(), String> {
+        if self.total() == 0 {
+            return Err("an alias needs a function with a positive weight".to_string());
+        }
+        Ok(())
+    }
+
+    fn total(&self) -> u64 {
+        self.weights.values().map(|&w| w as u64).sum()
+    }
+
+    /// Return the function the `n`th of `total` requests goes to, `n` < `total`
+    fn pick(&self, mut n: u64) -> Option<&str> {
+        for (function, &weight) in &self.weights {
+            if n < weight as u64 {
+                return Some(function);
+            }
+            n -= weight as u64;
+        }
+        None
+    }
+
+    /// Return the function a request to the alias goes to, None if all weights are 0
+    pub fn route(&self) -> Option<&str> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        self.pick(rand::thread_rng().gen_range(0..total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let canary: Alias = serde_yaml::from_str("{grader@v1: 3, grader@v2: 1, grader@v3: 0}").unwrap();
+        assert!(canary.validate().is_ok());
+        assert_eq!(canary.pick(0), Some("grader@v1"));
+        assert_eq!(canary.pick(2), Some("grader@v1"));
+        assert_eq!(canary.pick(3), Some("grader@v2"));
+        assert_eq!(canary.pick(4), None);
+
+        let routed: Vec<&str> = (0..1000).filter_map(|_| canary.route()).collect();
+        assert_eq!(routed.len(), 1000);
+        assert!(!routed.contains(&"grader@v3"));
+        let v2 = routed.iter().filter(|&&f| f == "grader@v2").count();
+        assert!(v2 > 150 && v2 < 350);
+
+        let nowhere: Alias = serde_yaml::from_str("{grader@v1: 0}").unwrap();
+        assert!(nowhere.validate().is_err());
+        assert_eq!(nowhere.route(), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Per-VM cgroups that limit and account the resources of `firerunner` processes
+//!
+//! With `cgroups` set in the controller YAML, `Vm::launch` moves each `firerunner` process into
+//! a cgroup of its own, `PARENT/FUNCTION/vm-ID` under the cgroup v2 mount:
+//!
+//! ```yaml
+//! cgroups:
+//!   root: /sys/fs/cgroup    # default
+//!   parent: snapfaas        # default
+//!   memory_overhead: 64     # MB the VMM may use on top of the VM's memory, default
+//! ```
+//!
+//! The limits are derived from the function's `FunctionConfig`: a CPU quota of `vcpus` CPUs,
+//! a memory limit of `memory` plus `memory_overhead` MB and an IO weight proportional to
+//! `vcpus`. Workers record the CPU time a VM spends on each request and the VM's peak memory
+//! in `RequestTimestamps::usage`. The cgroup is removed in the background once the VM is
+//! dropped and its `firerunner` has exited.
+//!
+//! Only the unified (v2) hierarchy is supported, the `cgroups` crate handles v1 only.
+//! The controllers `cpu`, `memory` and `io` must be enabled in the root's `cgroup.subtree_control`.
+//! `init` enables them below, at controller start, for the parent and the functions' cgroups.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::configs::FunctionConfig;
+
+/// CFS period of the CPU quota, in us
+const CPU_PERIOD: u64 = 100_000;
+/// IO weight of a VM per vcpu, the default IO weight of a cgroup being 100
+const IO_WEIGHT_PER_VCPU: u64 = 100;
+const CONTROLLERS: &str = "+cpu +memory +io";
+/// how many times, 10 ms apart, removing a cgroup is tried while its process exits
+const REMOVE_ATTEMPTS: usize = 100;
+
+lazy_static::lazy_static! {
+    // cgroups of dropped VMs to remove, see `remove_cgroups`
+    static ref REMOVER: Mutex<Sender<PathBuf>> = {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || remove_cgroups(rx));
+        Mutex::new(tx)
+    };
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CgroupConfig {
+    /// mount point of the cgroup v2 hierarchy
+    pub root: String,
+    /// cgroup, relative to `root`, the cgroups of VMs are created under
+    pub parent: String,
+    /// memory in MB a VM's cgroup may use on top of the VM's memory, for the VMM itself
+    pub memory_overhead: usize,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        CgroupConfig {
+            root: "/sys/fs/cgroup".to_string(),
+            parent: "snapfaas".to_string(),
+            memory_overhead: 64,
+        }
+    }
+}
+
+/// Resources used by a VM while serving a request
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Usage {
+    /// CPU time in us the VM spent on the request
+    pub cpu_time: u64,
+    /// highest memory usage in bytes of the VM, from its launch to the request's completion
+    pub peak_memory: u64,
+}
+
+/// Create the parent cgroup and the cgroups of `functions` and enable the controllers in them.
+/// Fail if `config.root` is not a cgroup v2 hierarchy.
+pub fn init<'a>(config: &CgroupConfig, functions: impl IntoIterator<Item = &'a String>) -> io::Result<()> {
+    let root = PathBuf::from(&config.root);
+    if !root.join("cgroup.controllers").is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+            format!("{:?} is not a cgroup v2 hierarchy", root)));
+    }
+    // controllers must be enabled in every ancestor of the VMs' cgroups
+    let parent = root.join(&config.parent);
+    fs::create_dir_all(&parent)?;
+    fs::write(parent.join("cgroup.subtree_control"), CONTROLLERS)?;
+    for function in functions {
+        init_function(&parent.join(function))?;
+    }
+    Ok(())
+}
+
+// Create the cgroup of a function and enable the controllers in it, unless it exists
+fn init_function(path: &Path) -> io::Result<()> {
+    match fs::create_dir(path) {
+        Ok(()) => fs::write(path.join("cgroup.subtree_control"), CONTROLLERS),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The cgroup of a launched VM
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create the cgroup of VM `id` of `function`, set its limits and move process `pid` into it.
+    /// The parent cgroup must have been set up with `init`.
+    pub fn create(config: &CgroupConfig, function: &str, id: usize, function_config: &FunctionConfig, pid: u32) -> io::Result<Self> {
+        // functions deployed after `init` get their cgroup on their first launch
+        let function_path = Path::new(&config.root).join(&config.parent).join(function);
+        init_function(&function_path)?;
+        let path = function_path.join(format!("vm-{}", id));
+        fs::create_dir_all(&path)?;
+        let cgroup = Cgroup { path };
+
+        let vcpus = function_config.vcpus;
+        let memory = (function_config.memory + config.memory_overhead) as u64 * (1 << 20);
+        let io_weight = (vcpus * IO_WEIGHT_PER_VCPU).max(1).min(10000);
+        for (file, value) in [
+            ("cpu.max", format!("{} {}", vcpus * CPU_PERIOD, CPU_PERIOD)),
+            ("memory.max", memory.to_string()),
+            ("io.weight", format!("default {}", io_weight)),
+        ] {
+            // a limit the kernel does not support does not keep the VM from running
+            if let Err(e) = fs::write(cgroup.path.join(file), value) {
+                warn!("Failed to set {} of cgroup {:?}: {:?}", file, cgroup.path, e);
+            }
+        }
+        fs::write(cgroup.path.join("cgroup.procs"), pid.to_string())?;
+        Ok(cgroup)
+    }
+
+    /// Return the CPU time in us used by the cgroup's processes so far
+    pub fn cpu_time(&self) -> io::Result<u64> {
+        let stat = fs::read_to_string(self.path.join("cpu.stat"))?;
+        stat.lines()
+            .find_map(|line| line.strip_prefix("usage_usec "))
+            .and_then(|usage| usage.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no usage_usec in cpu.stat"))
+    }
+
+    /// Return the highest memory usage in bytes of the cgroup so far. Kernels without
+    /// `memory.peak` (before 5.19) report the current usage instead.
+    pub fn peak_memory(&self) -> io::Result<u64> {
+        let peak = fs::read_to_string(self.path.join("memory.peak"))
+            .or_else(|_| fs::read_to_string(self.path.join("memory.current")))?;
+        peak.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Return the resources used since the cgroup's CPU time was `cpu_time`
+    pub fn usage_since(&self, cpu_time: u64) -> io::Result<Usage> {
+        Ok(Usage {
+            cpu_time: self.cpu_time()?.saturating_sub(cpu_time),
+            peak_memory: self.peak_memory()?,
+        })
+    }
+}
+
+impl Drop for Cgroup {
+    /// Hand the cgroup to the background thread that removes it once the killed `firerunner`
+    /// has exited, so that dropping VMs does not hold up the resource manager
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        if let Err(e) = REMOVER.lock().unwrap().send(path) {
+            error!("Failed to remove cgroup {:?}", e.0);
+        }
+    }
+}
+
+// Remove the cgroups received from `paths`, each of which is busy until its process has exited
+fn remove_cgroups(paths: mpsc::Receiver<PathBuf>) {
+    for path in paths {
+        let mut result = fs::remove_dir(&path);
+        for _ in 1..REMOVE_ATTEMPTS {
+            if result.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            result = fs::remove_dir(&path);
+        }
+        if let Err(e) = result {
+            error!("Failed to remove cgroup {:?}: {:?}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup() {
+        let root = tempfile::tempdir().unwrap();
+        let config = CgroupConfig { root: root.path().to_str().unwrap().to_string(), ..Default::default() };
+        let function = FunctionConfig { vcpus: 2, memory: 128, ..Default::default() };
+        let functions = vec!["hello".to_string()];
+        assert!(init(&config, &functions).is_err());
+
+        fs::write(root.path().join("cgroup.controllers"), "cpu memory io").unwrap();
+        init(&config, &functions).unwrap();
+        let cgroup = Cgroup::create(&config, "hello", 1, &function, 42).unwrap();
+        let path = root.path().join("snapfaas/hello/vm-1");
+        let read = |file: &str| fs::read_to_string(path.join(file)).unwrap();
+        assert_eq!(read("../../cgroup.subtree_control"), CONTROLLERS);
+        assert_eq!(read("../cgroup.subtree_control"), CONTROLLERS);
+        // a function deployed later gets its cgroup on its first launch
+        drop(Cgroup::create(&config, "later", 1, &function, 43).unwrap());
+        assert_eq!(fs::read_to_string(root.path().join("snapfaas/later/cgroup.subtree_control")).unwrap(), CONTROLLERS);
+        assert_eq!(read("cpu.max"), "200000 100000");
+        assert_eq!(read("memory.max"), (192u64 << 20).to_string());
+        assert_eq!(read("io.weight"), "default 200");
+        assert_eq!(read("cgroup.procs"), "42");
+
+        fs::write(path.join("cpu.stat"), "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\n").unwrap();
+        fs::write(path.join("memory.current"), "1048576\n").unwrap();
+        assert_eq!(cgroup.usage_since(1000).unwrap(), Usage { cpu_time: 500, peak_memory: 1 << 20 });
+        fs::write(path.join("memory.peak"), "2097152\n").unwrap();
+        assert_eq!(cgroup.peak_memory().unwrap(), 2 << 20);
+    }
+}
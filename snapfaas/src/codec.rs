@@ -0,0 +1,261 @@
+//! Wire protocol between the TCP gateway and its clients
+//!
+//! Every message is a frame: an 8-byte big-endian length followed by that many bytes of JSON.
+//! A client opens a connection with a `Hello` frame that announces the newest protocol version
+//! and the capabilities it supports. The gateway answers with a `Hello` that carries the
+//! negotiated version, the capabilities both sides support and the largest frame the gateway
+//! accepts. Afterwards, the client sends `Request` frames and the gateway answers with `Response`
+//! frames. Clients that skip the handshake speak version 0 which has no capabilities.
+//!
+//! A peer that announces a frame larger than the maximum frame size gets an error response
+//! instead of having the frame read into memory.
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::request::{Request, Response};
+
+/// Newest protocol version this crate speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// `async`: requests may set `"async": true`, see `results`.
+/// `pipelining`: requests may carry a correlation `"id"`, see `request::Request`.
+pub const CAPABILITIES: &[&str] = &["async", "pipelining"];
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// largest frame the sender accepts in bytes
+    pub max_frame_size: usize,
+}
+
+impl Hello {
+    /// Return the `Hello` announcing everything this crate supports
+    pub fn new(max_frame_size: usize) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            max_frame_size,
+        }
+    }
+
+    /// Return the reply to a peer's `Hello`: the older of the two versions and
+    /// the capabilities both sides support
+    pub fn negotiate(&self, peer: &Hello) -> Self {
+        Hello {
+            version: std::cmp::min(self.version, peer.version),
+            capabilities: self.capabilities.iter()
+                .filter(|c| peer.capabilities.contains(c))
+                .cloned()
+                .collect(),
+            max_frame_size: self.max_frame_size,
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Handshake {
+    hello: Hello,
+}
+
+/// Reads and writes frames on a stream, typically a `TcpStream`
+#[derive(Debug)]
+pub struct Codec<S> {
+    stream: S,
+    max_frame_size: usize,
+    // the outcome of the handshake, None if there was none (yet)
+    negotiated: Option<Hello>,
+    // whether a frame was read, a handshake is only valid as the first frame
+    started: bool,
+}
+
+impl Codec<TcpStream> {
+    /// Connect to a gateway and perform the handshake
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        let mut codec = Codec::new(TcpStream::connect(addr)?, DEFAULT_MAX_FRAME_SIZE);
+        codec.handshake()?;
+        Ok(codec)
+    }
+}
+
+impl<S: Read + Write> Codec<S> {
+    pub fn new(stream: S, max_frame_size: usize) -> Self {
+        Codec {
+            stream,
+            max_frame_size,
+            negotiated: None,
+            started: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Return the negotiated protocol parameters, None if the peer skipped the handshake
+    pub fn negotiated(&self) -> Option<&Hello> {
+        self.negotiated.as_ref()
+    }
+
+    /// Read a frame and return its content.
+    /// It fails with `ErrorKind::InvalidData` if the frame is empty or larger than
+    /// the maximum frame size, in which case the rest of the stream should be discarded.
+    pub fn read_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        self.started = true;
+        let mut buf = [0; 8];
+        self.stream.read_exact(&mut buf)?;
+        let size = u64::from_be_bytes(buf);
+
+        if size == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Empty frame"));
+        }
+        if size > self.max_frame_size as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Frame of {} bytes exceeds the maximum frame size of {} bytes", size, self.max_frame_size),
+            ));
+        }
+        let mut buf = vec![0; size as usize];
+        self.stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write_frame(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let size = (buf.len() as u64).to_be_bytes();
+        self.stream.write_all(&size)?;
+        self.stream.write_all(buf)?;
+        self.stream.flush()
+    }
+
+    /// Read a frame and parse it as JSON
+    pub fn read<T: DeserializeOwned>(&mut self) -> std::io::Result<T> {
+        let buf = self.read_frame()?;
+        serde_json::from_slice(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Write `msg` as a JSON frame
+    pub fn write<T: Serialize>(&mut self, msg: &T) -> std::io::Result<()> {
+        let buf = serde_json::to_vec(msg).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.write_frame(&buf)
+    }
+
+    /// Client side of the handshake
+    pub fn handshake(&mut self) -> std::io::Result<&Hello> {
+        self.write(&Handshake { hello: Hello::new(self.max_frame_size) })?;
+        let Handshake { hello } = self.read()?;
+        if hello.version == 0 || hello.version > PROTOCOL_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported protocol version {}", hello.version),
+            ));
+        }
+        self.negotiated = Some(hello);
+        Ok(self.negotiated.as_ref().unwrap())
+    }
+
+    /// Client side: send a request and wait for its response
+    pub fn invoke(&mut self, req: &Request) -> std::io::Result<Response> {
+        let buf = req.to_vec();
+        if let Some(hello) = self.negotiated.as_ref() {
+            if buf.len() > hello.max_frame_size {
+                return Err(Error::new(ErrorKind::InvalidInput, "Request exceeds the gateway's maximum frame size"));
+            }
+        }
+        self.write_frame(&buf)?;
+        self.read()
+    }
+
+    /// Server side: read the next request. If the client opens the connection with a
+    /// handshake, answer it first.
+    pub fn read_request(&mut self) -> std::io::Result<Request> {
+        let first = !self.started;
+        let mut buf = self.read_frame()?;
+        if first {
+            if let Ok(Handshake { hello }) = serde_json::from_slice::<Handshake>(&buf) {
+                if hello.version == 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Unsupported protocol version 0"));
+                }
+                let reply = Hello::new(self.max_frame_size).negotiate(&hello);
+                self.write(&Handshake { hello: reply.clone() })?;
+                self.negotiated = Some(reply);
+                buf = self.read_frame()?;
+            }
+        }
+        serde_json::from_slice(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::os::unix::net::UnixStream;
+    use crate::request::RequestStatus;
+
+    #[test]
+    fn test_handshake_and_invoke() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut server = Codec::new(server, 1024);
+            let req = server.read_request().unwrap();
+            assert_eq!(req.function, "hello");
+            server.write(&Response::completed("{\"greeting\": \"hi\"}")).unwrap();
+            server.negotiated().cloned()
+        });
+
+        let mut client = Codec::new(client, DEFAULT_MAX_FRAME_SIZE);
+        let hello = client.handshake().unwrap().clone();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        assert!(hello.supports("pipelining"));
+        assert_eq!(hello.max_frame_size, 1024);
+
+        let req = Request { function: "hello".to_string(), ..Default::default() };
+        let rsp = client.invoke(&req).unwrap();
+        assert_eq!(rsp.status, RequestStatus::SentToVM);
+        assert_eq!(rsp.output, serde_json::json!({ "greeting": "hi" }));
+        assert_eq!(handle.join().unwrap(), Some(hello));
+
+        // the gateway's maximum frame size is enforced on the client side
+        let req = Request { function: "hello".to_string(), payload: "x".repeat(2048).into(), ..Default::default() };
+        assert_eq!(client.invoke(&req).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_legacy_client() {
+        let req = Request { function: "hello".to_string(), ..Default::default() };
+        let mut codec = Codec::new(Cursor::new(Vec::new()), DEFAULT_MAX_FRAME_SIZE);
+        codec.write(&req).unwrap();
+        codec.stream.set_position(0);
+        assert_eq!(codec.read_request().unwrap().function, "hello");
+        assert!(codec.negotiated().is_none());
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut buf = u64::MAX.to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        let mut codec = Codec::new(Cursor::new(buf), DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(codec.read_frame().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut codec = Codec::new(Cursor::new(vec![0; 8]), DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(codec.read_frame().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let ours = Hello::new(DEFAULT_MAX_FRAME_SIZE);
+        let peer = Hello { version: 7, capabilities: vec!["async".to_string(), "unknown".to_string()], max_frame_size: 1 };
+        let reply = ours.negotiate(&peer);
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert_eq!(reply.capabilities, vec!["async".to_string()]);
+        assert_eq!(reply.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+    }
+}
@@ -1,18 +1,25 @@
 //! ResourceManager and function configuration
 //! In-memory data structures that represent controller configuration and
 //! function configurations
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_yaml;
 use url::Url;
-use log::{info, debug};
+use log::{info, debug, warn};
 
 use std::fs::File;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+use crate::alias::Alias;
+use crate::cgroup::CgroupConfig;
 use crate::convert_fs_path_to_url;
+use crate::eviction::Eviction;
+use crate::prediction::PredictionConfig;
+use crate::request::Priority;
+use crate::tenant::TenantConfig;
+use crate::workflow::Workflow;
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct ResourceManagerConfig {
     pub allow_network: bool,
     pub firerunner_path: String,
@@ -23,78 +30,161 @@ pub struct ResourceManagerConfig {
     #[serde(default)]
     pub snapshot_dir: Option<String>,
     pub functions: BTreeMap<String, FunctionConfig>,
+    /// workflows composing `functions`, see `workflow`
+    #[serde(default)]
+    pub workflows: BTreeMap<String, Workflow>,
+    /// aliases routing requests to versions of `functions`, see `alias`
+    #[serde(default)]
+    pub aliases: BTreeMap<String, Alias>,
+    /// policy choosing idle VMs to evict when memory runs out, see `eviction`
+    #[serde(default)]
+    pub eviction: Eviction,
+    /// pre-warm and retire idle VMs from predicted arrivals, see `prediction`. Disabled if None.
+    #[serde(default)]
+    pub prediction: Option<PredictionConfig>,
+    /// place VMs in cgroups limiting their resources, see `cgroup`. Disabled if None.
+    #[serde(default)]
+    pub cgroups: Option<CgroupConfig>,
+    /// tenants sharing the controller's memory, see `tenant`
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantConfig>,
 }
 
 impl ResourceManagerConfig {
     /// Create in-memory ResourceManagerConfig struct from a YAML file
     pub fn new(path: &str) -> Self {
+        ResourceManagerConfig::load(path).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `new`, but return an error instead of panicking if the file is not a valid
+    /// configuration, e.g., when reloading the configuration of a running controller
+    pub fn load(path: &str) -> Result<Self, String> {
         // TODO: Currently only supports file://localhost urls
-        let config_url = convert_fs_path_to_url(path).expect("Invalid configuration file path");
+        let config_url = convert_fs_path_to_url(path)
+            .map_err(|e| format!("Invalid configuration file path: {:?}", e))?;
         info!("Using controller config: {}", config_url);
 
         ResourceManagerConfig::initialize(&config_url)
     }
 
-    fn initialize(config_url: &str) -> Self {
-        if let Ok(config_url) = Url::parse(config_url) {
-            // populate a ResourceManagerConfig struct from the yaml file
-            if let Ok(f) = File::open(config_url.path()) {
-                let config: serde_yaml::Result<ResourceManagerConfig> = serde_yaml::from_reader(f);
-                match config {
-                    Ok(mut config) => {
-                        ResourceManagerConfig::convert_to_url(&mut config);
-                        ResourceManagerConfig::build_full_path_fs_images(&mut config);
-                        debug!("ResourceManager config: {:?}", config);
-                        config
-                    },
-                    Err(e) => panic!("Invalid YAML file {:?}", e)
-                }
-            } else {
-                panic!("Invalid local path to config file");
+    fn initialize(config_url: &str) -> Result<Self, String> {
+        let config_url = Url::parse(config_url).map_err(|_| "Invalid URL to config file".to_string())?;
+        // populate a ResourceManagerConfig struct from the yaml file
+        let f = File::open(config_url.path()).map_err(|_| "Invalid local path to config file".to_string())?;
+        let mut config: ResourceManagerConfig = serde_yaml::from_reader(f)
+            .map_err(|e| format!("Invalid YAML file {:?}", e))?;
+        for (name, tenant) in &config.tenants {
+            tenant.validate().map_err(|e| format!("Invalid tenant {:?}: {}", name, e))?;
+        }
+        for (name, function) in &config.functions {
+            config.check_function(name, function)?;
+        }
+        ResourceManagerConfig::check_workflows(&config)?;
+        ResourceManagerConfig::check_aliases(&config)?;
+        ResourceManagerConfig::convert_to_url(&mut config)?;
+        ResourceManagerConfig::build_full_path_fs_images(&mut config);
+        debug!("ResourceManager config: {:?}", config);
+        Ok(config)
+    }
+
+    /// Check that function `name` is consistent and can be resolved against the directories
+    /// of this configuration
+    pub fn check_function(&self, name: &str, function: &FunctionConfig) -> Result<(), String> {
+        check_name(name)?;
+        if function.reserved_concurrency > function.concurrency_limit {
+            return Err(format!("Function {:?} reserves more VMs than its concurrency limit", name));
+        }
+        if function.max_idle.map_or(false, |max| function.min_idle > max) {
+            return Err(format!("Function {:?} has a min_idle larger than its max_idle", name));
+        }
+        if let Some(tenant) = function.tenant.as_ref() {
+            if !self.tenants.contains_key(tenant) {
+                return Err(format!("Function {:?} belongs to tenant {:?}, which is not declared", name, tenant));
             }
+        }
+        if function.appfs.is_some() && self.appfs_dir.is_none() {
+            return Err("Appfs directory not specified".to_string());
+        }
+        if function.load_dir.is_some() && self.snapshot_dir.is_none() {
+            return Err("Snapshot directory not specified".to_string());
+        }
+        Ok(())
+    }
 
-        } else {
-            panic!("Invalid URL to config file")
+    fn check_workflows(config: &ResourceManagerConfig) -> Result<(), String> {
+        for (name, workflow) in &config.workflows {
+            if config.functions.contains_key(name) {
+                return Err(format!("Workflow {:?} has the same name as a function", name));
+            }
+            if let Err(e) = workflow.validate(&config.functions, &config.aliases) {
+                return Err(format!("Invalid workflow {:?}: {}", name, e));
+            }
         }
+        Ok(())
     }
 
-    fn convert_to_url(config: &mut ResourceManagerConfig) {
-        config.kernel_path = convert_fs_path_to_url(&config.kernel_path)
-            .expect("Invalid kernel path");
-        config.runtimefs_dir = convert_fs_path_to_url(&config.runtimefs_dir)
-            .expect("Invalid runtimefs directory");
-        config.appfs_dir = config.appfs_dir.as_ref().map(|dir| convert_fs_path_to_url(dir)
-            .expect("Invalid appfs directory"));
-        config.snapshot_dir = config.snapshot_dir.as_ref().map(|dir| convert_fs_path_to_url(dir)
-            .expect("Invalid snapshot directory"));
+    // Functions an alias routes to may also be registered at runtime (see `registry`),
+    // so unknown functions are only warned about
+    fn check_aliases(config: &ResourceManagerConfig) -> Result<(), String> {
+        for (name, alias) in &config.aliases {
+            if config.functions.contains_key(name) || config.workflows.contains_key(name) {
+                return Err(format!("Alias {:?} has the same name as a function or a workflow", name));
+            }
+            alias.validate().map_err(|e| format!("Invalid alias {:?}: {}", name, e))?;
+            for function in alias.weights.keys().filter(|f| !config.functions.contains_key(*f)) {
+                warn!("Alias {:?} routes to function {:?}, which is not declared", name, function);
+            }
+        }
+        Ok(())
+    }
+
+    fn convert_to_url(config: &mut ResourceManagerConfig) -> Result<(), String> {
+        let convert = |path: &str, what: &str| convert_fs_path_to_url(path)
+            .map_err(|e| format!("Invalid {}: {:?}", what, e));
+        config.kernel_path = convert(&config.kernel_path, "kernel path")?;
+        config.runtimefs_dir = convert(&config.runtimefs_dir, "runtimefs directory")?;
+        config.appfs_dir = config.appfs_dir.as_ref().map(|dir| convert(dir, "appfs directory")).transpose()?;
+        config.snapshot_dir = config.snapshot_dir.as_ref().map(|dir| convert(dir, "snapshot directory")).transpose()?;
+        Ok(())
     }
 
     fn build_full_path_fs_images(config: &mut ResourceManagerConfig) {
-        let runtimefs_base = config.get_runtimefs_base();
-        let maybe_appfs_base = config.get_appfs_base();
-        let maybe_snapshot_base = config.get_snapshot_base();
-        let kernel_path = &config.kernel_path;
-        for app in config.functions.values_mut() {
-            // build full path to the runtimefs
-            app.runtimefs = [ &runtimefs_base, &app.runtimefs ]
-                .iter().collect::<PathBuf>().to_str().unwrap().to_string();
-            // build full path to the appfs
-            app.appfs = app.appfs.as_ref()
-                .map(|d| [ maybe_appfs_base.as_ref().expect("Appfs directory not specified"), d ]
-                     .iter().collect::<PathBuf>().to_str().unwrap().to_string());
-            app.load_dir = app.load_dir.as_ref().map(|s| s.split(',').collect::<Vec<&str>>().iter()
-                    .map(|s| [ maybe_snapshot_base.as_ref().expect("Snapshot directory not specified").as_str(), s ]
-                         .iter().collect::<PathBuf>().to_str().unwrap().to_string())
-                    .collect::<Vec<String>>().join(","));
-            // TODO: currently all apps use the same kernel
-            app.kernel = Url::parse(kernel_path)
-                .expect("Bad kernel path URL").path().to_string();
-            // use `firerunner`'s default DEFAULT_KERNEL_CMDLINE
-            // defined in firecracker/vmm/lib.rs
-            app.cmdline = None;
-            // `snapctr` does not support generate snapshots
-            app.dump_dir = None;
+        let mut functions = std::mem::take(&mut config.functions);
+        for app in functions.values_mut() {
+            config.build_full_path(app);
         }
+        config.functions = functions;
+    }
+
+    /// Resolve the file system images of `app` against the directories of this configuration
+    pub fn build_full_path(&self, app: &mut FunctionConfig) {
+        let runtimefs_base = self.get_runtimefs_base();
+        let maybe_appfs_base = self.get_appfs_base();
+        let maybe_snapshot_base = self.get_snapshot_base();
+        // build full path to the runtimefs
+        app.runtimefs = [ &runtimefs_base, &app.runtimefs ]
+            .iter().collect::<PathBuf>().to_str().unwrap().to_string();
+        // build full path to the appfs
+        app.appfs = app.appfs.as_ref()
+            .map(|d| [ maybe_appfs_base.as_ref().expect("Appfs directory not specified"), d ]
+                 .iter().collect::<PathBuf>().to_str().unwrap().to_string());
+        app.load_dir = app.load_dir.as_ref().map(|s| s.split(',').collect::<Vec<&str>>().iter()
+                .map(|s| [ maybe_snapshot_base.as_ref().expect("Snapshot directory not specified").as_str(), s ]
+                     .iter().collect::<PathBuf>().to_str().unwrap().to_string())
+                .collect::<Vec<String>>().join(","));
+        // TODO: currently all apps use the same kernel
+        app.kernel = Url::parse(&self.kernel_path)
+            .expect("Bad kernel path URL").path().to_string();
+        // use `firerunner`'s default DEFAULT_KERNEL_CMDLINE
+        // defined in firecracker/vmm/lib.rs
+        app.cmdline = None;
+        // `snapctr` does not support generate snapshots
+        app.dump_dir = None;
+    }
+
+    /// Return whether `name` is a function, a workflow or an alias of this configuration
+    pub fn declares(&self, name: &str) -> bool {
+        self.functions.contains_key(name) || self.workflows.contains_key(name) || self.aliases.contains_key(name)
     }
 
     pub fn get_runtimefs_base(&self) -> String {
@@ -110,7 +200,16 @@ impl ResourceManagerConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Check that `name` can name a function. Function names also name directories, e.g., the
+/// function's cgroup, so they cannot contain `/` or be `.` or `..`.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(format!("{:?} is not a valid function name", name));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FunctionConfig {
     /// enable network
     #[serde(default)]
@@ -124,7 +223,32 @@ pub struct FunctionConfig {
     pub vcpus: u64,
     /// VM memory size
     pub memory: usize,
-    pub concurrency_limit: usize, // not in use
+    /// maximum number of VMs of the function serving requests at the same time.
+    /// Requests beyond it are rejected with `RequestStatus::Overloaded`.
+    pub concurrency_limit: usize,
+    /// number of VMs whose memory is set aside for the function, other functions
+    /// cannot allocate it or evict its idle VMs below this number
+    #[serde(default)]
+    pub reserved_concurrency: usize,
+    /// number of idle VMs launched ahead of demand, see `prewarm`
+    #[serde(default)]
+    pub min_idle: usize,
+    /// maximum number of idle VMs kept, VMs released beyond it are shut down
+    #[serde(default)]
+    pub max_idle: Option<usize>,
+    /// time in ms an idle VM is kept before it is shut down, unless the function is left with
+    /// fewer than `min_idle` idle VMs. Idle VMs are only evicted under memory pressure if None.
+    #[serde(default)]
+    pub keep_alive: Option<u64>,
+    /// maximum number of queued requests to the function, see `queue`
+    #[serde(default)]
+    pub queue_limit: Option<usize>,
+    /// default priority class of requests to the function, see `queue`
+    #[serde(default)]
+    pub priority: Priority,
+    /// tenant whose quotas the function's VMs count against, see `tenant`
+    #[serde(default)]
+    pub tenant: Option<String>,
     /// base snapshot
     #[serde(default)]
     pub load_dir: Option<String>,
@@ -152,6 +276,30 @@ pub struct FunctionConfig {
     /// load the working set
     #[serde(default)]
     pub load_ws: bool,
+    /// default maximum time in ms a request may take to respond, unbounded if None
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+impl FunctionConfig {
+    /// Return whether VMs created with either config behave the same, i.e., whether the configs
+    /// only differ in how the resource manager and the request queue treat the function
+    pub fn same_vm(&self, other: &FunctionConfig) -> bool {
+        self.network == other.network
+            && self.runtimefs == other.runtimefs
+            && self.appfs == other.appfs
+            && self.vcpus == other.vcpus
+            && self.memory == other.memory
+            && self.load_dir == other.load_dir
+            && self.copy_base == other.copy_base
+            && self.copy_diff == other.copy_diff
+            && self.kernel == other.kernel
+            && self.cmdline == other.cmdline
+            && self.dump_dir == other.dump_dir
+            && self.dump_ws == other.dump_ws
+            && self.load_ws == other.load_ws
+            && self.timeout == other.timeout
+    }
 }
 
 impl Default for FunctionConfig {
@@ -163,7 +311,14 @@ impl Default for FunctionConfig {
             appfs: None,
             vcpus: 1,
             memory: 128,
-            concurrency_limit: 1, // not in use
+            concurrency_limit: 1,
+            reserved_concurrency: 0,
+            min_idle: 0,
+            max_idle: None,
+            keep_alive: None,
+            queue_limit: None,
+            priority: Priority::Normal,
+            tenant: None,
             load_dir: None,
             //diff_dirs: None,
             copy_base: false,
@@ -172,6 +327,7 @@ impl Default for FunctionConfig {
             dump_dir: None,
             dump_ws: false,
             load_ws: false,
+            timeout: None,
         }
     }
 }
@@ -0,0 +1,153 @@
+//! Policies choosing which idle VMs the resource manager evicts to make room for a new one
+//!
+//! The resource manager keeps a `FunctionUsage` for every function and, when it needs memory,
+//! asks the policy selected by `eviction` in the controller YAML which of the functions with
+//! idle VMs to evict a VM of. It evicts the function's VM that has been idle longest and asks
+//! again until enough memory is free.
+//!
+//! * `lru` (default) evicts the function whose VMs were least recently handed out
+//! * `lfu` evicts the function whose VMs were handed out least often
+//! * `cost_aware` evicts the function cheapest to bring back, by memory × measured boot time,
+//!   so that large VMs that take long to boot stay warm
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// What the resource manager knows about the use of a function's VMs
+#[derive(Debug, Clone, Default)]
+pub struct FunctionUsage {
+    /// last time a VM of the function was handed out, None if never
+    pub last_used: Option<Instant>,
+    /// number of times a VM of the function was handed out
+    pub uses: u64,
+    /// moving average of the time its VMs took to launch
+    pub boot_time: Duration,
+    /// number of launches measured
+    pub boots: u64,
+}
+
+impl FunctionUsage {
+    /// Record that a VM of the function was handed out
+    pub fn record_use(&mut self) {
+        self.last_used = Some(Instant::now());
+        self.uses += 1;
+    }
+
+    /// Record that a VM of the function took `duration` to launch
+    pub fn record_boot(&mut self, duration: Duration) {
+        self.boot_time = if self.boots == 0 { duration } else { (self.boot_time * 3 + duration) / 4 };
+        self.boots += 1;
+    }
+}
+
+/// A function with at least one idle VM
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub function: &'a str,
+    /// memory of one of its VMs in MB
+    pub memory: usize,
+    pub usage: FunctionUsage,
+}
+
+pub trait EvictionPolicy: fmt::Debug + Send {
+    /// Return the index in `candidates` of the function to evict an idle VM of,
+    /// or None if none should be evicted
+    fn select(&self, candidates: &[Candidate]) -> Option<usize>;
+}
+
+/// Eviction policy, as named in the controller YAML
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    Lru,
+    Lfu,
+    CostAware,
+}
+
+impl Default for Eviction {
+    fn default() -> Self {
+        Eviction::Lru
+    }
+}
+
+impl Eviction {
+    pub fn policy(self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Eviction::Lru => Box::new(Lru),
+            Eviction::Lfu => Box::new(Lfu),
+            Eviction::CostAware => Box::new(CostAware),
+        }
+    }
+}
+
+fn select_min<K: Ord, F: Fn(&Candidate) -> K>(candidates: &[Candidate], key: F) -> Option<usize> {
+    candidates.iter().enumerate().min_by_key(|(_, c)| key(*c)).map(|(i, _)| i)
+}
+
+#[derive(Debug)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        select_min(candidates, |c| c.usage.last_used)
+    }
+}
+
+#[derive(Debug)]
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        select_min(candidates, |c| (c.usage.uses, c.usage.last_used))
+    }
+}
+
+#[derive(Debug)]
+pub struct CostAware;
+
+impl EvictionPolicy for CostAware {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        select_min(candidates, |c| (c.memory as u128 * c.usage.boot_time.as_micros(), c.usage.last_used))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(function: &str, memory: usize, uses: u64, boot_ms: u64) -> Candidate {
+        let mut usage = FunctionUsage::default();
+        for _ in 0..uses {
+            usage.record_use();
+        }
+        usage.record_boot(Duration::from_millis(boot_ms));
+        Candidate { function, memory, usage }
+    }
+
+    #[test]
+    fn test_policies() {
+        let popular = candidate("popular", 128, 10, 100);
+        let rare = candidate("rare", 128, 1, 100);
+        // used last, but only twice, and big and slow to boot
+        let big = candidate("big", 1024, 2, 2000);
+        let candidates = [popular, rare, big];
+
+        assert_eq!(Eviction::Lru.policy().select(&candidates), Some(0));
+        assert_eq!(Eviction::Lfu.policy().select(&candidates), Some(1));
+        assert_eq!(Eviction::CostAware.policy().select(&candidates), Some(0));
+        assert_eq!(Eviction::Lru.policy().select(&[]), None);
+
+        let config: Eviction = serde_yaml::from_str("cost_aware").unwrap();
+        assert_eq!(config, Eviction::CostAware);
+    }
+
+    #[test]
+    fn test_boot_time_average() {
+        let mut usage = FunctionUsage::default();
+        usage.record_boot(Duration::from_millis(100));
+        assert_eq!(usage.boot_time, Duration::from_millis(100));
+        usage.record_boot(Duration::from_millis(500));
+        assert_eq!(usage.boot_time, Duration::from_millis(200));
+    }
+}
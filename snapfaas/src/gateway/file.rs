@@ -0,0 +1,163 @@
+//! File gateway
+//!
+//! Replays a JSON Lines workload file (e.g., `resources/example-requests.json` or files
+//! generated by `macrobenchmark/generator.py`) through the worker pool. Each line is a request
+//! JSON string with an optional `time` field, the offset in ms from the start of the replay at
+//! which the request is sent. Lines without `time` are sent right away. The replay is
+//! open-loop: sending the next request does not wait for the previous one to complete.
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+use log::{error, debug, info};
+use serde::Deserialize;
+use time::precise_time_ns;
+
+use crate::request::{Request, RequestStatus, Response};
+use crate::metrics::RequestTimestamps;
+use crate::message::RequestInfo;
+use super::Gateway;
+
+#[derive(Debug, Deserialize)]
+struct FileRequest {
+    #[serde(default)]
+    time: Option<u64>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug)]
+pub struct FileGateway {
+    requests: Receiver<RequestInfo>,
+}
+
+impl FileGateway {
+    /// Start replaying the requests in file `path`.
+    /// If `exit_when_done` is true, the gateway waits for every request's response after the
+    /// last request is sent and then stops yielding requests. Otherwise, it keeps the
+    /// controller running after the replay finishes.
+    pub fn replay(path: &str, exit_when_done: bool) -> Self {
+        let file = File::open(path).expect("Failed to open requests file");
+        debug!("Gateway started replaying: {:?}", path);
+
+        let (requests_tx, requests_rx) = channel();
+
+        std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut pending: Vec<Receiver<Response>> = Vec::new();
+            for (lineno, line) in BufReader::new(file).lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Failed to read requests file: {:?}", e);
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let FileRequest { time, request } = match serde_json::from_str::<FileRequest>(&line) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        error!("request parsing failed at line {}: {:?}", lineno + 1, e);
+                        continue;
+                    }
+                };
+
+                if let Some(time) = time {
+                    let at = Duration::from_millis(time);
+                    let elapsed = start.elapsed();
+                    if at > elapsed {
+                        std::thread::sleep(at - elapsed);
+                    }
+                }
+
+                let timestamps = RequestTimestamps {
+                    at_gateway: precise_time_ns(),
+                    request: request.clone(),
+                    ..Default::default()
+                };
+                let (tx, rx) = channel::<Response>();
+                if requests_tx.send((request, tx, timestamps)).is_err() {
+                    return;
+                }
+                if exit_when_done {
+                    pending.push(rx);
+                }
+            }
+
+            if exit_when_done {
+                let total = pending.len();
+                let mut completed = 0;
+                for rx in pending {
+                    if let Ok(response) = rx.recv() {
+                        debug!("{:?}", response);
+                        if let RequestStatus::SentToVM = response.status {
+                            completed += 1;
+                        }
+                    }
+                }
+                info!("Replay finished. Total requests: {}, completed: {}", total, completed);
+                // dropping `requests_tx` here ends the iteration
+            } else {
+                info!("Replay finished. Waiting to be shut down");
+                loop {
+                    std::thread::park();
+                }
+            }
+        });
+
+        FileGateway {
+            requests: requests_rx,
+        }
+    }
+}
+
+impl Gateway for FileGateway {
+    fn listen(path: &str) -> Self {
+        FileGateway::replay(path, false)
+    }
+}
+
+impl Iterator for FileGateway {
+    type Item = RequestInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.requests.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_replay_and_exit() {
+        let mut temp = NamedTempFile::new().unwrap();
+        writeln!(temp, "{{\"time\": 100, \"user_id\": 2, \"function\": \"hello\", \"payload\": {{}}}}").unwrap();
+        writeln!(temp).unwrap();
+        writeln!(temp, "not a request").unwrap();
+        writeln!(temp, "{{\"function\": \"world\", \"payload\": 42}}").unwrap();
+
+        let start = Instant::now();
+        let mut gateway = FileGateway::replay(temp.path().to_str().unwrap(), true);
+
+        let (req, tx, _) = gateway.next().unwrap();
+        assert_eq!(req.function, "hello");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        tx.send(Response::completed("")).unwrap();
+
+        let (req, tx, _) = gateway.next().unwrap();
+        assert_eq!(req.function, "world");
+        assert_eq!(req.payload, serde_json::json!(42));
+        tx.send(Response::new(RequestStatus::Dropped)).unwrap();
+
+        // every response is in, so the gateway stops
+        assert!(gateway.next().is_none());
+    }
+}
@@ -0,0 +1,496 @@
+//! HTTP/1.1 gateway
+//!
+//! Clients invoke a function by sending `POST /invoke/{function}` with the function's JSON
+//! payload as the request body. The connection blocks until the function returns. The response
+//! body is the function's output on success. Otherwise it is the JSON encoded `Response` value
+//! and the status code is derived from its `RequestStatus`.
+//!
+//! `POST /invoke/{function}?async=true` returns `202 Accepted` right away with the body
+//! `{"id": ID}`. The result can then be fetched with `GET /results/{ID}`, which answers
+//! `202 Accepted` while the request is pending. `GET /results/{ID}?wait=SECS` long-polls for
+//! up to SECS seconds. `DELETE /results/{ID}` discards a result.
+//!
+//! A gateway created `with_registry` also lets clients deploy functions (see `registry`):
+//! `PUT /functions/{function}` registers the JSON `FunctionConfig` in the body, answering
+//! `201 Created`, or `200 OK` if it replaces a registered function. `DELETE /functions/{function}`
+//! unregisters a function and `GET /functions` lists the registered ones.
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use log::{error, debug};
+use serde_json::Value;
+use time::precise_time_ns;
+
+use crate::codec::DEFAULT_MAX_FRAME_SIZE;
+use crate::request::{Priority, Request, RequestStatus, Response};
+use crate::metrics::RequestTimestamps;
+use crate::message::RequestInfo;
+use crate::results::{self, AsyncResult};
+use crate::configs::FunctionConfig;
+use crate::registry::{self, Registry};
+use super::Gateway;
+
+const INVOKE_PREFIX: &str = "/invoke/";
+const RESULTS_PREFIX: &str = "/results/";
+const FUNCTIONS_PATH: &str = "/functions";
+// upper bound on how long a client can long-poll for a result
+const MAX_WAIT_SECS: u64 = 60;
+// bounds on what a single client can make the gateway buffer before the body
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// e.g. "HTTP/1.1"
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Return the value of the first header named `name`, case-insensitively
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return whether the connection persists after the response. HTTP/1.1 connections do
+    /// unless the client asks to close them, HTTP/1.0 ones only if it asks to keep them alive.
+    fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HTTPGateway {
+    requests: Receiver<RequestInfo>,
+}
+
+impl HTTPGateway {
+    /// Listen on `addr` and reject request bodies larger than `max_body_size` bytes
+    pub fn new(addr: &str, max_body_size: usize) -> Self {
+        HTTPGateway::start(addr, max_body_size, None)
+    }
+
+    /// Like `new`, but also serve the `/functions` endpoints that deploy functions to `registry`
+    pub fn with_registry(addr: &str, max_body_size: usize, registry: Registry) -> Self {
+        HTTPGateway::start(addr, max_body_size, Some(registry))
+    }
+
+    fn start(addr: &str, max_body_size: usize, registry: Option<Registry>) -> Self {
+        let listener = TcpListener::bind(addr).expect("listener failed to bind");
+        debug!("HTTP gateway started listening on: {:?}", addr);
+
+        let (requests_tx, requests_rx) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    debug!("connection from {:?}", stream.peer_addr());
+                    let requests = requests_tx.clone();
+                    let registry = registry.clone();
+                    std::thread::spawn(move || {
+                        let peer = stream.peer_addr();
+                        if let Err(e) = handle_connection(stream, requests, registry, max_body_size) {
+                            error!("Failed to respond to HTTP client at {:?}: {:?}", peer, e);
+                        }
+                    });
+                }
+            }
+        });
+
+        HTTPGateway {
+            requests: requests_rx,
+        }
+    }
+}
+
+impl Gateway for HTTPGateway {
+    fn listen(addr: &str) -> Self {
+        HTTPGateway::new(addr, DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Iterator for HTTPGateway {
+    type Item = RequestInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.requests.recv().ok()
+    }
+}
+
+/// Serve HTTP requests on one connection until the client closes it or asks to close it
+fn handle_connection(
+    stream: TcpStream,
+    requests: Sender<RequestInfo>,
+    registry: Option<Registry>,
+    max_body_size: usize,
+) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let http_req = match read_request(&mut reader, max_body_size) {
+            Ok(req) => req,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                let body = error_body(&e.to_string());
+                return write_response(&mut writer, 413, "Payload Too Large", &body, false);
+            }
+            Err(e) => {
+                let body = error_body(&e.to_string());
+                return write_response(&mut writer, 400, "Bad Request", &body, false);
+            }
+        };
+        let keep_alive = http_req.keep_alive();
+        let (code, reason, body) = route(&http_req, &requests, registry.as_ref());
+        write_response(&mut writer, code, reason, &body, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn route(
+    http_req: &HttpRequest,
+    requests: &Sender<RequestInfo>,
+    registry: Option<&Registry>,
+) -> (u16, &'static str, Vec<u8>) {
+    let mut parts = http_req.path.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+    if let Some(function) = path.strip_prefix(INVOKE_PREFIX) {
+        if !function.is_empty() && !function.contains('/') {
+            return invoke(http_req, function, query, requests);
+        }
+    }
+    if let Some(id) = path.strip_prefix(RESULTS_PREFIX) {
+        if !id.is_empty() && !id.contains('/') {
+            return result(http_req, id, query);
+        }
+    }
+    if let (Some(name), Some(registry)) = (path.strip_prefix(FUNCTIONS_PATH), registry) {
+        if name.is_empty() {
+            return list_functions(http_req, registry);
+        }
+        if let Some(name) = name.strip_prefix('/') {
+            if !name.is_empty() && !name.contains('/') {
+                return deploy(http_req, name, registry);
+            }
+        }
+    }
+    (404, "Not Found", error_body("unknown endpoint"))
+}
+
+/// List the registered functions
+fn list_functions(http_req: &HttpRequest, registry: &Registry) -> (u16, &'static str, Vec<u8>) {
+    if http_req.method != "GET" {
+        return (405, "Method Not Allowed", error_body("use GET to list functions"));
+    }
+    (200, "OK", serde_json::to_vec(&registry.functions()).unwrap())
+}
+
+/// Register or unregister function `name`
+fn deploy(http_req: &HttpRequest, name: &str, registry: &Registry) -> (u16, &'static str, Vec<u8>) {
+    let res = match http_req.method.as_str() {
+        "PUT" => match serde_json::from_slice::<FunctionConfig>(&http_req.body) {
+            Ok(function) => registry.register(name, function)
+                .map(|replaced| if replaced { (200, "OK") } else { (201, "Created") }),
+            Err(e) => return (400, "Bad Request", error_body(&format!("invalid function config: {}", e))),
+        },
+        "DELETE" => registry.unregister(name).map(|_| (200, "OK")),
+        _ => return (405, "Method Not Allowed", error_body("use PUT or DELETE on functions")),
+    };
+    match res {
+        Ok((code, reason)) => (code, reason, Vec::new()),
+        Err(registry::Error::Invalid(e)) => (400, "Bad Request", error_body(&e)),
+        Err(registry::Error::Conflict(e)) => (409, "Conflict", error_body(&e)),
+        Err(registry::Error::NotFound) => (404, "Not Found", error_body("unknown function")),
+        Err(registry::Error::Persist(e)) => {
+            error!("Failed to persist the function registry: {:?}", e);
+            (500, "Internal Server Error", error_body("failed to persist the function registry"))
+        }
+    }
+}
+
+/// Forward an invocation to the worker pool and block until it responds, unless it is
+/// asynchronous (`?async=true`). `?timeout=MS` bounds how long the function may run and
+/// `?priority=low|normal|high` sets the request's priority class.
+fn invoke(
+    http_req: &HttpRequest,
+    function: &str,
+    query: &str,
+    requests: &Sender<RequestInfo>,
+) -> (u16, &'static str, Vec<u8>) {
+    if http_req.method != "POST" {
+        return (405, "Method Not Allowed", error_body("use POST to invoke a function"));
+    }
+    let is_async = query_param(query, "async").map_or(false, |v| v == "true" || v == "1");
+    let timeout = match query_param(query, "timeout").map(|v| v.parse::<u64>()) {
+        Some(Ok(timeout)) => Some(timeout),
+        Some(Err(_)) => return (400, "Bad Request", error_body("invalid timeout")),
+        None => None,
+    };
+    let priority = match query_param(query, "priority").map(|v| v.parse::<Priority>()) {
+        Some(Ok(priority)) => Some(priority),
+        Some(Err(e)) => return (400, "Bad Request", error_body(&e)),
+        None => None,
+    };
+    let payload = if http_req.body.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice(&http_req.body) {
+            Ok(payload) => payload,
+            Err(e) => return (400, "Bad Request", error_body(&format!("invalid JSON payload: {}", e))),
+        }
+    };
+
+    let req = Request {
+        function: function.to_string(),
+        payload,
+        is_async,
+        timeout,
+        priority,
+        ..Default::default()
+    };
+    if is_async {
+        return to_http(super::submit_async(req, requests));
+    }
+    let timestamps = RequestTimestamps {
+        at_gateway: precise_time_ns(),
+        request: req.clone(),
+        ..Default::default()
+    };
+    let (tx, rx) = channel::<Response>();
+    if requests.send((req, tx, timestamps)).is_err() {
+        return (503, "Service Unavailable", error_body("controller is shutting down"));
+    }
+    match rx.recv() {
+        Ok(response) => to_http(response),
+        Err(_) => (500, "Internal Server Error", error_body("request dropped")),
+    }
+}
+
+/// Fetch, long-poll for or discard the result of an asynchronous request
+fn result(http_req: &HttpRequest, id: &str, query: &str) -> (u16, &'static str, Vec<u8>) {
+    match http_req.method.as_str() {
+        "GET" => {
+            let wait = query_param(query, "wait").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+            let res = if wait > 0 {
+                results::wait(id, Duration::from_secs(std::cmp::min(wait, MAX_WAIT_SECS)))
+            } else {
+                results::get(id)
+            };
+            match res {
+                Some(AsyncResult::Done(response)) => to_http(response),
+                Some(AsyncResult::Pending) => to_http(Response::new(RequestStatus::Accepted(id.to_string()))),
+                None => (404, "Not Found", error_body("unknown request ID")),
+            }
+        }
+        "DELETE" => {
+            if results::remove(id) {
+                (200, "OK", Vec::new())
+            } else {
+                (404, "Not Found", error_body("unknown request ID"))
+            }
+        }
+        _ => (405, "Method Not Allowed", error_body("use GET or DELETE on results")),
+    }
+}
+
+/// Return the value of parameter `name` in a query string
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&')
+        .map(|kv| kv.split_once('=').unwrap_or((kv, "")))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+fn to_http(response: Response) -> (u16, &'static str, Vec<u8>) {
+    let (code, reason) = status_code(&response.status);
+    match response.status {
+        // a workflow's response also carries the result of each step
+        RequestStatus::SentToVM if !response.steps.is_empty() => (code, reason, response.to_vec()),
+        RequestStatus::SentToVM => (code, reason, response.output.to_string().into_bytes()),
+        RequestStatus::Accepted(id) => (code, reason, serde_json::json!({ "id": id }).to_string().into_bytes()),
+        _ => (code, reason, response.to_vec()),
+    }
+}
+
+/// Map a request's final status to an HTTP status code and reason phrase
+fn status_code(status: &RequestStatus) -> (u16, &'static str) {
+    match status {
+        RequestStatus::SentToVM => (200, "OK"),
+        RequestStatus::Accepted(_) => (202, "Accepted"),
+        RequestStatus::FunctionNotExist => (404, "Not Found"),
+        RequestStatus::BadRequest(_) => (400, "Bad Request"),
+        RequestStatus::ResourceExhausted => (503, "Service Unavailable"),
+        RequestStatus::Timeout => (504, "Gateway Timeout"),
+        RequestStatus::Overloaded => (429, "Too Many Requests"),
+        RequestStatus::InvokeRejected => (508, "Loop Detected"),
+        RequestStatus::LaunchFailed | RequestStatus::ExecutionFailed |
+        RequestStatus::Dropped => (500, "Internal Server Error"),
+    }
+}
+
+fn error_body(msg: &str) -> Vec<u8> {
+    serde_json::json!({ "error": msg }).to_string().into_bytes()
+}
+
+/// Read one CRLF (or LF) terminated line and return it without the line terminator
+fn read_line<R: BufRead>(reader: &mut R) -> std::io::Result<String> {
+    let mut line = String::new();
+    let len = reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)?;
+    if len == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+    }
+    if !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "Line too long"));
+    }
+    Ok(line.trim_end_matches(|c| c == '\r' || c == '\n').to_string())
+}
+
+/// Read a complete HTTP request, including its body, from `reader`.
+/// Only bodies delimited by `Content-Length` are supported. A body larger than `max_body_size`
+/// fails with `ErrorKind::InvalidInput` and other malformed requests with `ErrorKind::InvalidData`.
+fn read_request<R: BufRead>(reader: &mut R, max_body_size: usize) -> std::io::Result<HttpRequest> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") =>
+            (method.to_string(), path.to_string(), version.to_string()),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Malformed request line")),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Error::new(ErrorKind::InvalidData, "Too many headers"));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+            None => return Err(Error::new(ErrorKind::InvalidData, "Malformed header")),
+        }
+    }
+
+    let mut req = HttpRequest { method, path, version, headers, body: Vec::new() };
+    if req.header("transfer-encoding").is_some() {
+        return Err(Error::new(ErrorKind::InvalidData, "Transfer-Encoding is not supported"));
+    }
+    if let Some(len) = req.header("content-length") {
+        let len = len.parse::<usize>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?;
+        if len > max_body_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Body of {} bytes exceeds the maximum of {} bytes", len, max_body_size),
+            ));
+        }
+        req.body = vec![0; len];
+        reader.read_exact(&mut req.body)?;
+    }
+    Ok(req)
+}
+
+fn write_response<W: Write>(
+    writer: &mut W,
+    code: u16,
+    reason: &str,
+    body: &[u8],
+    keep_alive: bool,
+) -> std::io::Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", code, reason)?;
+    write!(writer, "Content-Type: application/json\r\n")?;
+    write!(writer, "Content-Length: {}\r\n", body.len())?;
+    // HTTP/1.0 clients only keep the connection if told so
+    write!(writer, "Connection: {}\r\n", if keep_alive { "keep-alive" } else { "close" })?;
+    write!(writer, "\r\n")?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let mut raw: &[u8] = b"POST /invoke/hello HTTP/1.1\r\nHost: localhost\r\ncontent-length: 13\r\n\r\n{\"name\":\"a\"}\nGET /";
+        let req = read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/invoke/hello");
+        assert_eq!(req.header("Content-Length"), Some("13"));
+        assert_eq!(req.body, b"{\"name\":\"a\"}\n".to_vec());
+        assert!(req.keep_alive());
+        // the next pipelined request is left in the reader
+        assert_eq!(raw, b"GET /");
+
+        // HTTP/1.0 connections close unless the client asks otherwise
+        let mut raw: &[u8] = b"GET /results/1 HTTP/1.0\r\n\r\n";
+        assert!(!read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap().keep_alive());
+        let mut raw: &[u8] = b"GET /results/1 HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n";
+        assert!(read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap().keep_alive());
+        let mut raw: &[u8] = b"GET /results/1 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(!read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap().keep_alive());
+    }
+
+    #[test]
+    fn test_read_request_malformed() {
+        let mut raw: &[u8] = b"POST /invoke/hello\r\n\r\n";
+        assert_eq!(read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut raw: &[u8] = b"POST /invoke/hello HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut raw: &[u8] = b"";
+        assert_eq!(read_request(&mut raw, DEFAULT_MAX_FRAME_SIZE).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let mut raw: &[u8] = b"POST /invoke/hello HTTP/1.1\r\nContent-Length: 1024\r\n\r\n";
+        assert_eq!(read_request(&mut raw, 1023).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_routing() {
+        let (tx, _rx) = channel();
+        let req = HttpRequest {
+            method: "GET".to_string(),
+            path: "/invoke/hello".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: vec![],
+            body: vec![],
+        };
+        assert_eq!(route(&req, &tx, None).0, 405);
+
+        let req = HttpRequest { method: "POST".to_string(), path: "/hello".to_string(), ..req };
+        assert_eq!(route(&req, &tx, None).0, 404);
+
+        let req = HttpRequest { path: "/invoke/hello?async=true".to_string(), body: b"{".to_vec(), ..req };
+        assert_eq!(route(&req, &tx, None).0, 400);
+
+        let req = HttpRequest { path: "/results/".to_string(), ..req };
+        assert_eq!(route(&req, &tx, None).0, 404);
+
+        // deploying functions is disabled without a registry
+        let req = HttpRequest { method: "PUT".to_string(), path: "/functions/hello".to_string(), ..req };
+        assert_eq!(route(&req, &tx, None).0, 404);
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("async=true&wait=5", "wait"), Some("5"));
+        assert_eq!(query_param("async", "async"), Some(""));
+        assert_eq!(query_param("", "wait"), None);
+    }
+}
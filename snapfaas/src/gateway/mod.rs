@@ -0,0 +1,184 @@
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use log::{error, debug};
+
+use crate::codec::{Codec, DEFAULT_MAX_FRAME_SIZE};
+use crate::request::{self, RequestStatus};
+use crate::metrics::RequestTimestamps;
+use crate::message::RequestInfo;
+use crate::results;
+
+mod http;
+mod file;
+
+pub use self::http::HTTPGateway;
+pub use self::file::FileGateway;
+
+/// Maximum number of pipelined requests a TCP connection may have awaiting their responses.
+/// Requests beyond it are responded to with `RequestStatus::Overloaded` right away.
+pub const MAX_PIPELINED: usize = 64;
+
+/// A gateway listens on a endpoint and accepts requests
+/// For example a FileGateway "listens" to a file and accepts
+/// each line as request JSON string.
+/// A TCPGateway listens on a TCP port and accepts length-prefixed
+/// request JSON strings (see `codec`).
+/// A HTTPGateway listens on a TCP port and accepts requests from
+/// HTTP POST commands.
+pub trait Gateway {
+    fn listen(source: &str) -> Self
+        where Self: std::marker::Sized;
+}
+
+#[derive(Debug)]
+pub struct TCPGateway {
+    requests: Receiver<RequestInfo>,
+}
+
+impl TCPGateway {
+    /// Listen on `addr` and reject frames larger than `max_frame_size` bytes
+    pub fn new(addr: &str, max_frame_size: usize) -> Self {
+        let listener = TcpListener::bind(addr).expect("listener failed to bind");
+        debug!("Gateway started listening on: {:?}", addr);
+
+        let (requests_tx, requests_rx) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    debug!("connection from {:?}", stream.peer_addr());
+                    let requests = requests_tx.clone();
+                    std::thread::spawn(move || {
+                        // responses to pipelined requests are written by their own threads
+                        let writer = match stream.try_clone() {
+                            Ok(writer) => Arc::new(Mutex::new(Codec::new(writer, max_frame_size))),
+                            Err(e) => {
+                                error!("Failed to clone TCP stream: {:?}", e);
+                                return;
+                            }
+                        };
+                        let mut reader = Codec::new(stream, max_frame_size);
+                        // pipelined requests awaiting their responses, each waited on by a thread
+                        let in_flight = Arc::new(AtomicUsize::new(0));
+                        loop {
+                            let req = match reader.read_request() {
+                                Ok(req) => req,
+                                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                                    error!("request parsing failed: {:?}", e);
+                                    respond(&writer, &request::Response::new(RequestStatus::BadRequest(e.to_string())));
+                                    return;
+                                }
+                                // the client closed the connection
+                                Err(_) => return,
+                            };
+
+                            let id = req.id;
+                            if req.is_async {
+                                let mut response = submit_async(req, &requests);
+                                response.id = id;
+                                respond(&writer, &response);
+                                continue;
+                            }
+
+                            if id.is_some() && in_flight.load(Ordering::SeqCst) >= MAX_PIPELINED {
+                                let mut response = request::Response::new(RequestStatus::Overloaded);
+                                response.id = id;
+                                respond(&writer, &response);
+                                continue;
+                            }
+
+                            use time::precise_time_ns;
+                            let timestamps = RequestTimestamps {
+                                at_gateway: precise_time_ns(),
+                                request: req.clone(),
+                                ..Default::default()
+                            };
+                            let (tx, rx) = channel::<request::Response>();
+                            // every request is answered, `Dropped` if the controller lets it go
+                            let dropped = move || request::Response { id, ..request::Response::new(RequestStatus::Dropped) };
+                            if requests.send((req, tx, timestamps)).is_err() {
+                                error!("Failed to forward request, the controller is gone");
+                                respond(&writer, &dropped());
+                                continue;
+                            }
+                            if id.is_none() {
+                                // without a correlation ID, the client expects
+                                // responses in order
+                                respond(&writer, &rx.recv().unwrap_or_else(|_| dropped()));
+                            } else {
+                                let writer = writer.clone();
+                                let in_flight = in_flight.clone();
+                                in_flight.fetch_add(1, Ordering::SeqCst);
+                                std::thread::spawn(move || {
+                                    let mut response = rx.recv().unwrap_or_else(|_| dropped());
+                                    response.id = id;
+                                    respond(&writer, &response);
+                                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                                });
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        TCPGateway{
+            requests: requests_rx,
+        }
+    }
+}
+
+impl Gateway for TCPGateway {
+    fn listen(addr: &str) -> Self {
+        TCPGateway::new(addr, DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Iterator for TCPGateway {
+    type Item = RequestInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.requests.recv().ok()
+    }
+}
+
+/// Write a response to a TCP client. The lock keeps concurrent responses from interleaving.
+fn respond(writer: &Mutex<Codec<TcpStream>>, response: &request::Response) {
+    let mut codec = writer.lock().unwrap();
+    if let Err(e) = codec.write(response) {
+        error!("Failed to respond to TCP client at {:?}: {:?}", codec.get_ref().peer_addr(), e);
+    }
+}
+
+/// Register an asynchronous request in the result store and forward it to the workers.
+/// Return the response that tells the client the ID its result will be stored under.
+fn submit_async(mut req: request::Request, requests: &Sender<RequestInfo>) -> request::Response {
+    use time::precise_time_ns;
+    let status = match results::register() {
+        Ok(id) => {
+            req.async_id = Some(id.clone());
+            let timestamps = RequestTimestamps {
+                at_gateway: precise_time_ns(),
+                request: req.clone(),
+                ..Default::default()
+            };
+            // the worker stores the response, nobody waits on the channel
+            let (tx, _) = channel::<request::Response>();
+            if requests.send((req, tx, timestamps)).is_ok() {
+                RequestStatus::Accepted(id)
+            } else {
+                results::remove(&id);
+                RequestStatus::Dropped
+            }
+        }
+        Err(e) => {
+            error!("Failed to register asynchronous request: {:?}", e);
+            RequestStatus::Dropped
+        }
+    };
+    request::Response::new(status)
+}
@@ -19,6 +19,7 @@ lazy_static::lazy_static! {
     pub static ref DBENV: lmdb::Environment = {
         let dbenv = lmdb::Environment::new()
             .set_map_size(100 * 1024 * 1024 * 1024)
+            .set_max_dbs(5)
             .open(std::path::Path::new("storage"))
             .unwrap();
 
@@ -1,8 +1,10 @@
 extern crate glob;
 
 pub mod request;
+pub mod codec;
 pub mod worker;
 pub mod message;
+pub mod queue;
 pub mod gateway;
 pub mod configs;
 pub mod resource_manager;
@@ -12,6 +14,16 @@ pub mod metrics;
 pub mod firecracker_wrapper;
 pub mod blobstore;
 pub mod labeled_fs;
+pub mod results;
+pub mod workflow;
+pub mod trace;
+pub mod eviction;
+pub mod prewarm;
+pub mod prediction;
+pub mod registry;
+pub mod alias;
+pub mod cgroup;
+pub mod tenant;
 
 use std::string::String;
 use std::fs::{self, File};
@@ -1,6 +1,7 @@
 use std::sync::mpsc::Sender;
 
 use crate::request::{Request, Response};
+use crate::configs::ResourceManagerConfig;
 use crate::vm::Vm;
 use crate::resource_manager;
 use crate::metrics::RequestTimestamps;
@@ -14,4 +15,10 @@ pub enum Message {
     GetVm(String, Sender<Result<Vm, resource_manager::Error>>),
     ReleaseVm(Vm),
     DeleteVm(Vm),
+    /// ask for an unlaunched VM to pre-warm, None if no function needs one, see `prewarm`
+    GetPrewarmVm(Sender<Option<Vm>>),
+    Prewarmed(Vm),
+    PrewarmFailed(Vm),
+    /// replace the resource manager's configuration, see `ResourceManager::reload`
+    Reload(ResourceManagerConfig),
 }
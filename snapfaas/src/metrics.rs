@@ -5,11 +5,14 @@ use std::fs::File;
 
 use log::error;
 use serde_json;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::request::Request;
+use crate::cgroup::Usage;
+use crate::request::{Request, Timings};
+use crate::trace;
 
-#[derive(Default, Debug, Serialize)]
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RequestTimestamps {
     /// time a request arrives at the gateway
     pub at_gateway: u64,
@@ -23,7 +26,18 @@ pub struct RequestTimestamps {
     pub launched: u64,
     /// response returned time, 0 if execution fails
     pub completed: u64,
-    /// request in bytes
+    /// whether a new VM was launched for the request
+    pub cold_start: bool,
+    /// number of queued requests, including this one, when the request was queued
+    pub queue_depth: usize,
+    /// value to add to the timestamps above, which count from boot, to turn them into Unix time
+    /// (see `trace::unix_offset`), 0 in records of workers that did not record it
+    pub unix_offset: u64,
+    /// resources the VM used serving the request, if VMs are placed in cgroups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// request in bytes, including its trace context, which links it to the request that
+    /// invoked it through `parent_span_id`
     pub request: Request,
 }
 
@@ -31,11 +45,34 @@ impl RequestTimestamps {
     pub fn to_json(&self) -> String {
         serde_json::to_string(&self).unwrap()
     }
+
+    /// Break the timestamps down into the time spent in each stage.
+    /// Stages that were not reached take 0 ns.
+    pub fn timings(&self) -> Timings {
+        // requests invoked by a VM never pass the gateway
+        let start = if self.at_gateway != 0 { self.at_gateway } else { self.at_vmm };
+        Timings {
+            queueing: span(start, self.arrived),
+            allocation: span(self.arrived, self.allocated),
+            launch: if self.cold_start { span(self.allocated, self.launched) } else { 0 },
+            execution: span(self.launched, self.completed),
+            cold_start: self.cold_start,
+        }
+    }
+}
+
+fn span(from: u64, to: u64) -> u64 {
+    if from == 0 || to == 0 {
+        0
+    } else {
+        to.saturating_sub(from)
+    }
 }
 
 #[derive(Debug)]
 pub struct WorkerMetrics {
     log_file: File,
+    trace_file: Option<File>,
     request_timestamps: Arc<Mutex<Vec<RequestTimestamps>>>,
 }
 
@@ -43,22 +80,26 @@ impl WorkerMetrics {
     pub fn new(log_file: File) -> Self {
         WorkerMetrics {
             log_file,
+            trace_file: None,
             request_timestamps: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    /// Also export the traced requests of each flush to `trace_file`,
+    /// as one line of OTLP JSON (see `trace::to_otlp`)
+    pub fn set_trace_file(&mut self, trace_file: File) {
+        self.trace_file = Some(trace_file);
+    }
+
     pub fn start_timed_flush(&self, interval: u64) {
         let reqtsps = Arc::clone(&self.request_timestamps);
         let mut log_file = self.log_file.try_clone().unwrap();
+        let mut trace_file = self.trace_file.as_ref().map(|f| f.try_clone().unwrap());
         thread::spawn(move || {
             loop {
                 thread::sleep(time::Duration::from_secs(interval));
                 let tsps = &mut *reqtsps.lock().unwrap();
-                for t in tsps.as_slice() {
-                    if let Err(e) = writeln!(&mut log_file, "{}", t.to_json()) {
-                        error!("failed to flush worker metrics: {:?}", e);
-                    }
-                }
+                write_records(&mut log_file, trace_file.as_mut(), tsps);
                 tsps.truncate(0);
             }
         });
@@ -72,11 +113,7 @@ impl WorkerMetrics {
     /// manual flush
     pub fn flush(mut self) {
         let tsps = &*self.request_timestamps.lock().unwrap();
-        for t in tsps.as_slice() {
-            if let Err(e) = writeln!(&mut self.log_file, "{}", t.to_json()) {
-                error!("failed to flush worker metrics: {:?}", e);
-            }
-        }
+        write_records(&mut self.log_file, self.trace_file.as_mut(), tsps);
     }
 
     pub fn len(&self) -> usize {
@@ -84,6 +121,22 @@ impl WorkerMetrics {
     }
 }
 
+fn write_records(log_file: &mut File, trace_file: Option<&mut File>, tsps: &[RequestTimestamps]) {
+    for t in tsps {
+        if let Err(e) = writeln!(log_file, "{}", t.to_json()) {
+            error!("failed to flush worker metrics: {:?}", e);
+        }
+    }
+    if let Some(trace_file) = trace_file {
+        if tsps.iter().any(|t| t.request.trace.is_some()) {
+            let otlp = trace::to_otlp(tsps, trace::unix_offset());
+            if let Err(e) = writeln!(trace_file, "{}", otlp) {
+                error!("failed to export traces: {:?}", e);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
@@ -148,4 +201,66 @@ mod tests {
         }
         assert_eq!(counter, 3);
     }
+
+    #[test]
+    fn test_timings() {
+        let warm = RequestTimestamps {
+            at_gateway: 100,
+            arrived: 150,
+            allocated: 160,
+            launched: 161,
+            completed: 200,
+            ..Default::default()
+        };
+        let t = warm.timings();
+        assert_eq!((t.queueing, t.allocation, t.launch, t.execution, t.cold_start), (50, 10, 0, 39, false));
+
+        // nested invocation that failed to launch
+        let failed = RequestTimestamps {
+            at_vmm: 100,
+            arrived: 120,
+            allocated: 130,
+            cold_start: true,
+            ..Default::default()
+        };
+        let t = failed.timings();
+        assert_eq!((t.queueing, t.allocation, t.launch, t.execution, t.cold_start), (20, 10, 0, 0, true));
+    }
+
+    #[test]
+    fn test_trace_export() {
+        let stat = NamedTempFile::new().unwrap();
+        let traces = NamedTempFile::new().unwrap();
+        let mut m = WorkerMetrics::new(stat.reopen().unwrap());
+        m.set_trace_file(traces.reopen().unwrap());
+
+        let root = trace::TraceContext::root();
+        let child = root.child();
+        m.push(RequestTimestamps {
+            at_gateway: 100,
+            completed: 200,
+            request: Request { function: "caller".to_string(), trace: Some(root.clone()), ..Default::default() },
+            ..Default::default()
+        });
+        m.push(RequestTimestamps {
+            at_vmm: 150,
+            completed: 180,
+            request: Request { function: "callee".to_string(), trace: Some(child), ..Default::default() },
+            ..Default::default()
+        });
+        m.flush();
+
+        // the statistics record the link from the callee to its caller
+        let lines: Vec<String> = BufReader::new(stat.reopen().unwrap()).lines().map(Result::unwrap).collect();
+        assert_eq!(lines.len(), 2);
+        let callee: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(callee["request"]["trace"]["parent_span_id"], serde_json::json!(root.span_id));
+
+        let lines: Vec<String> = BufReader::new(traces.reopen().unwrap()).lines().map(Result::unwrap).collect();
+        assert_eq!(lines.len(), 1);
+        let otlp: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        let spans = otlp["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[1]["parentSpanId"], spans[0]["spanId"]);
+    }
 }
@@ -0,0 +1,264 @@
+//! Predictive pre-warming and retiring of idle VMs from the arrival history of functions
+//!
+//! The policy follows the hybrid histogram policy of "Serverless in the Wild" (ATC'20). For every
+//! function, the resource manager keeps a histogram of the times between consecutive requests
+//! and the number of requests in each hour of the day. From the histogram it derives a window
+//! after each request: a VM of the function is kept warm only from the `head_percentile` to the
+//! `tail_percentile` of the inter-arrival times, widened by `margin`. Before the window idle VMs
+//! are retired, the pre-warmer launches one when the window opens and it is retired again once
+//! the window closes.
+//!
+//! A histogram with fewer than `min_samples` inter-arrival times, or with most of them beyond its
+//! range, does not predict much. For such functions, a VM is kept warm for `fallback_keep_alive`
+//! after each request and during the hours of the day busier than average.
+//!
+//! `sfprewarm` replays the requests recorded in `out/thread-*.stat` against the policy to tune it.
+use std::time::Duration;
+
+use serde::Deserialize;
+
+const HOURS_PER_DAY: usize = 24;
+
+/// The `prediction` section of the controller YAML, times in ms
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PredictionConfig {
+    /// width of a histogram bin
+    pub bin_width: u64,
+    /// number of bins, inter-arrival times beyond `bin_width * num_bins` are out of bounds
+    pub num_bins: usize,
+    /// percentile of the inter-arrival times at which the warm window opens
+    pub head_percentile: f64,
+    /// percentile of the inter-arrival times at which the warm window closes
+    pub tail_percentile: f64,
+    /// fraction by which the window is widened on both ends
+    pub margin: f64,
+    /// number of inter-arrival times needed before the histogram is trusted
+    pub min_samples: u64,
+    /// how long a VM is kept warm after a request while the histogram is not trusted
+    pub fallback_keep_alive: u64,
+}
+
+impl Default for PredictionConfig {
+    fn default() -> Self {
+        PredictionConfig {
+            bin_width: 60 * 1000,
+            num_bins: 4 * 60,
+            head_percentile: 5.0,
+            tail_percentile: 99.0,
+            margin: 0.1,
+            min_samples: 10,
+            fallback_keep_alive: 10 * 60 * 1000,
+        }
+    }
+}
+
+/// Time after a request during which a VM of the function should be warm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub head: Duration,
+    pub tail: Duration,
+}
+
+/// Arrival statistics of a function. Times are durations since an arbitrary, fixed epoch,
+/// the Unix epoch for the resource manager.
+#[derive(Debug, Clone)]
+pub struct ArrivalHistory {
+    bins: Vec<u64>,
+    out_of_bounds: u64,
+    hourly: [u64; HOURS_PER_DAY],
+    last_arrival: Option<Duration>,
+}
+
+fn hour_of_day(at: Duration) -> usize {
+    (at.as_secs() / 3600) as usize % HOURS_PER_DAY
+}
+
+impl ArrivalHistory {
+    pub fn new(config: &PredictionConfig) -> Self {
+        ArrivalHistory {
+            bins: vec![0; config.num_bins],
+            out_of_bounds: 0,
+            hourly: [0; HOURS_PER_DAY],
+            last_arrival: None,
+        }
+    }
+
+    /// Record a request arriving at `at`
+    pub fn record(&mut self, at: Duration, config: &PredictionConfig) {
+        if let Some(last) = self.last_arrival {
+            let bin = (at.saturating_sub(last).as_millis() / config.bin_width.max(1) as u128) as usize;
+            match self.bins.get_mut(bin) {
+                Some(count) => *count += 1,
+                None => self.out_of_bounds += 1,
+            }
+        }
+        self.hourly[hour_of_day(at)] += 1;
+        self.last_arrival = Some(self.last_arrival.map_or(at, |last| last.max(at)));
+    }
+
+    pub fn last_arrival(&self) -> Option<Duration> {
+        self.last_arrival
+    }
+
+    /// Return the warm window, or None if the histogram is not to be trusted yet
+    pub fn window(&self, config: &PredictionConfig) -> Option<Window> {
+        let in_bounds: u64 = self.bins.iter().sum();
+        if in_bounds + self.out_of_bounds < config.min_samples || self.out_of_bounds > in_bounds {
+            return None;
+        }
+        let bin_width = Duration::from_millis(config.bin_width);
+        let head = bin_width * self.percentile_bin(config.head_percentile, in_bounds) as u32;
+        let tail = bin_width * (self.percentile_bin(config.tail_percentile, in_bounds) + 1) as u32;
+        Some(Window {
+            head: head.mul_f64((1.0 - config.margin).max(0.0)),
+            tail: tail.mul_f64(1.0 + config.margin),
+        })
+    }
+
+    // Return the first bin at which `percentile` % of the in-bounds samples are reached
+    fn percentile_bin(&self, percentile: f64, in_bounds: u64) -> usize {
+        let target = (in_bounds as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.bins.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return i;
+            }
+        }
+        self.bins.len().saturating_sub(1)
+    }
+
+    // Whether the hour of the day of `at` has seen more requests than the average hour
+    fn busy_hour(&self, at: Duration, config: &PredictionConfig) -> bool {
+        let total: u64 = self.hourly.iter().sum();
+        total >= config.min_samples && self.hourly[hour_of_day(at)] * HOURS_PER_DAY as u64 > total
+    }
+
+    /// Return whether a VM of the function should be warm at `now`
+    pub fn should_be_warm(&self, now: Duration, config: &PredictionConfig) -> bool {
+        match self.last_arrival {
+            Some(last) => self.warm_after(now.saturating_sub(last), now, config),
+            None => false,
+        }
+    }
+
+    // Whether a VM should be warm `since` after the last request, at `at`
+    fn warm_after(&self, since: Duration, at: Duration, config: &PredictionConfig) -> bool {
+        match self.window(config) {
+            Some(window) => window.head <= since && since <= window.tail,
+            None => since <= Duration::from_millis(config.fallback_keep_alive) || self.busy_hour(at, config),
+        }
+    }
+
+    // How long a VM is kept warm between the last request and the next one, `gap` later
+    fn warm_time(&self, gap: Duration, at: Duration, config: &PredictionConfig) -> Duration {
+        match self.window(config) {
+            Some(window) if gap > window.head => gap.min(window.tail) - window.head,
+            Some(_) => Duration::from_secs(0),
+            None if self.busy_hour(at, config) => gap,
+            None => gap.min(Duration::from_millis(config.fallback_keep_alive)),
+        }
+    }
+}
+
+/// Outcome of replaying the requests to a function against a policy, one VM per function
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Evaluation {
+    pub requests: u64,
+    pub cold_starts: u64,
+    /// time a VM of the function is warm but idle
+    pub warm_time: Duration,
+}
+
+/// Replay the sorted `arrivals` of a function against the predictive policy,
+/// which learns from the arrivals as they come
+pub fn evaluate(arrivals: &[Duration], config: &PredictionConfig) -> Evaluation {
+    let mut history = ArrivalHistory::new(config);
+    let mut evaluation = Evaluation::default();
+    for &at in arrivals {
+        evaluation.requests += 1;
+        match history.last_arrival() {
+            Some(last) => {
+                let gap = at.saturating_sub(last);
+                if !history.warm_after(gap, at, config) {
+                    evaluation.cold_starts += 1;
+                }
+                evaluation.warm_time += history.warm_time(gap, at, config);
+            }
+            None => evaluation.cold_starts += 1,
+        }
+        history.record(at, config);
+    }
+    evaluation
+}
+
+/// Replay the sorted `arrivals` of a function against a fixed keep-alive policy
+pub fn evaluate_fixed(arrivals: &[Duration], keep_alive: Duration) -> Evaluation {
+    let mut evaluation = Evaluation::default();
+    let mut last: Option<Duration> = None;
+    for &at in arrivals {
+        evaluation.requests += 1;
+        match last {
+            Some(last) => {
+                let gap = at.saturating_sub(last);
+                if gap > keep_alive {
+                    evaluation.cold_starts += 1;
+                }
+                evaluation.warm_time += gap.min(keep_alive);
+            }
+            None => evaluation.cold_starts += 1,
+        }
+        last = Some(at);
+    }
+    evaluation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn test_window() {
+        let config = PredictionConfig { margin: 0.0, ..Default::default() };
+        let mut history = ArrivalHistory::new(&config);
+        // a request every 30 minutes
+        for i in 0..20 {
+            history.record(minutes(30 * i), &config);
+        }
+        assert_eq!(history.window(&config), Some(Window { head: minutes(30), tail: minutes(31) }));
+
+        let now = minutes(30 * 19);
+        assert!(!history.should_be_warm(now + minutes(5), &config));
+        assert!(history.should_be_warm(now + minutes(30), &config));
+        assert!(!history.should_be_warm(now + minutes(40), &config));
+
+        // too few samples for the histogram, fall back to the fixed keep-alive
+        let mut history = ArrivalHistory::new(&config);
+        history.record(minutes(0), &config);
+        assert_eq!(history.window(&config), None);
+        assert!(history.should_be_warm(minutes(5), &config));
+        assert!(!history.should_be_warm(minutes(30), &config));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let config = PredictionConfig::default();
+        let arrivals: Vec<Duration> = (0..50).map(|i| minutes(30 * i)).collect();
+
+        // a 10 minute keep-alive misses every request
+        let fixed = evaluate_fixed(&arrivals, minutes(10));
+        assert_eq!(fixed.cold_starts, 50);
+        assert_eq!(fixed.warm_time, minutes(10) * 49);
+
+        // the histogram learns to pre-warm just before each request
+        let predictive = evaluate(&arrivals, &config);
+        assert_eq!(predictive.requests, 50);
+        assert!(predictive.cold_starts < 15);
+        assert!(predictive.warm_time < fixed.warm_time);
+    }
+}
@@ -0,0 +1,88 @@
+//! Background task that launches VMs ahead of demand
+//!
+//! Every `interval`, and right away at startup, the pre-warmer asks the resource manager for
+//! unlaunched VMs of the functions that have fewer idle VMs than their `min_idle`, launches
+//! them and hands them back to be put on the idle lists. The resource manager only allocates
+//! VMs for pre-warming in free memory, it never evicts idle VMs to make room for them.
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::message::Message;
+use crate::queue::RequestQueue;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Prewarmer {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Prewarmer {
+    /// Start pre-warming. Like a worker's, the VMs launched connect
+    /// to the listener `worker-{cid}.sock_1234`.
+    pub fn new(
+        request_queue: RequestQueue,
+        vm_req_sender: Sender<Message>,
+        cid: u32,
+        interval: Duration,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let vm_listener_path = format!("worker-{}.sock_1234", cid);
+            let _ = std::fs::remove_file(&vm_listener_path);
+            let vm_listener = match UnixListener::bind(vm_listener_path) {
+                Ok(listener) => listener,
+                Err(e) => panic!("Failed to bind to unix listener \"worker-{}.sock_1234\": {:?}", cid, e),
+            };
+
+            loop {
+                // top up the idle lists, stop at the first failure to retry in the next round
+                loop {
+                    let (tx, rx) = mpsc::channel();
+                    if vm_req_sender.send(Message::GetPrewarmVm(tx)).is_err() {
+                        return;
+                    }
+                    let mut vm = match rx.recv() {
+                        Ok(Some(vm)) => vm,
+                        Ok(None) => break,
+                        // the resource manager shut down
+                        Err(_) => return,
+                    };
+                    let vm_listener_dup = match vm_listener.try_clone() {
+                        Ok(listener) => listener,
+                        Err(e) => panic!("Failed to clone unix listener \"worker-{}.sock_1234\": {:?}", cid, e),
+                    };
+                    match vm.launch(Some(request_queue.clone()), vm_listener_dup, cid, false, None) {
+                        Ok(()) => {
+                            debug!("Pre-warmed VM {} of function {:?}", vm.id(), vm.function_name());
+                            let _ = vm_req_sender.send(Message::Prewarmed(vm));
+                        }
+                        Err(e) => {
+                            error!("Failed to pre-warm a VM of function {:?}: {:?}", vm.function_name(), e);
+                            let _ = vm_req_sender.send(Message::PrewarmFailed(vm));
+                            break;
+                        }
+                    }
+                }
+
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => return,
+                }
+            }
+        });
+
+        Prewarmer { stop, thread }
+    }
+
+    /// Stop pre-warming once the VM being launched, if any, is handed back
+    pub fn shutdown(self) -> thread::Result<()> {
+        let _ = self.stop.send(());
+        self.thread.join()
+    }
+}
@@ -0,0 +1,516 @@
+//! Bounded priority queue of requests in front of the worker pool
+//!
+//! Gateways and VMs (through the invoke syscall) submit requests to a `RequestQueue` and
+//! workers take them out with `RequestQueue::recv`. The queue admits a request only if fewer
+//! than `max_depth` requests in total, and fewer than the function's `queue_limit` requests
+//! to the same function, are waiting for a worker. Otherwise it responds right away with
+//! `RequestStatus::Overloaded`.
+//!
+//! Each request belongs to a priority class, its own `priority` or else its function's.
+//! Workers serve higher classes first and each class in FIFO order. To keep lower classes
+//! from starving, a request that has waited longer than the starvation limit is served
+//! before any other request, oldest first.
+//!
+//! The queue also sets the deadline of each request it admits from the request's `timeout` or
+//! else its function's, so that the time spent waiting counts against the timeout.
+//!
+//! Requests that functions make through the invoke syscall carry the chain of functions that
+//! led to them. The queue rejects them with `RequestStatus::InvokeRejected` if the chain is
+//! deeper than `InvokeLimits::max_depth` or, unless cycles are allowed, already contains the
+//! callee, so that a function invoking itself, directly or not, cannot flood the queue.
+//!
+//! Workers taking requests out with `RequestQueue::recv_invoked` only serve requests invoked by
+//! functions. Keeping some workers for them lets callees run while all other workers are
+//! blocked in synchronous invocations, waiting for those callees.
+//!
+//! A request to an alias is routed to one of the alias's functions before anything else,
+//! see `alias`.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::alias::Alias;
+use crate::configs::FunctionConfig;
+use crate::message::{Message, RequestInfo};
+use crate::request::{Priority, Request, RequestError, RequestStatus, Response};
+use crate::trace::TraceContext;
+use crate::worker;
+
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+pub const DEFAULT_STARVATION_LIMIT: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_INVOKE_DEPTH: usize = 8;
+
+/// Limits on chains of functions invoking each other
+#[derive(Debug, Clone, Copy)]
+pub struct InvokeLimits {
+    /// maximum number of nested invocations below a request from a client
+    pub max_depth: usize,
+    /// whether a function may be invoked while it is already on the chain, e.g. A→B→A
+    pub allow_cycles: bool,
+}
+
+impl Default for InvokeLimits {
+    fn default() -> Self {
+        InvokeLimits {
+            max_depth: DEFAULT_MAX_INVOKE_DEPTH,
+            allow_cycles: false,
+        }
+    }
+}
+
+impl InvokeLimits {
+    /// Return the reason to reject `req`, if any
+    fn check(&self, req: &Request) -> Result<(), RequestError> {
+        if req.ancestry.len() > self.max_depth {
+            return Err(RequestError::InvokeDepthExceeded(self.max_depth));
+        }
+        if !self.allow_cycles && req.ancestry.contains(&req.function) {
+            let mut chain = req.ancestry.clone();
+            chain.push(req.function.clone());
+            return Err(RequestError::InvokeCycle(chain));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct FunctionPolicy {
+    // maximum number of queued requests, None for no limit other than the queue's depth
+    limit: Option<usize>,
+    priority: Priority,
+    // default timeout in ms
+    timeout: Option<u64>,
+}
+
+impl FunctionPolicy {
+    fn new(config: &FunctionConfig) -> Self {
+        FunctionPolicy {
+            limit: config.queue_limit,
+            priority: config.priority,
+            timeout: config.timeout,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // from priority class to its requests, in arrival order
+    classes: BTreeMap<Priority, VecDeque<(Instant, RequestInfo)>>,
+    total: usize,
+    functions: HashMap<String, usize>,
+    // number of workers asked to shut down
+    shutdown: usize,
+}
+
+impl State {
+    /// Remove and return the next request to serve, among the requests invoked by functions
+    /// only if `invoked_only`
+    fn pop(&mut self, starvation_limit: Duration, invoked_only: bool) -> Option<RequestInfo> {
+        let now = Instant::now();
+        // the first request each class may serve, with its position and the time it was queued
+        let heads: Vec<(Priority, usize, Instant)> = self.classes.iter()
+            .filter_map(|(class, requests)| {
+                requests.iter()
+                    .position(|(_, (req, _, _))| !invoked_only || !req.ancestry.is_empty())
+                    .map(|i| (*class, i, requests[i].0))
+            })
+            .collect();
+        let starved = heads.iter()
+            .filter(|(_, _, queued)| now.duration_since(*queued) >= starvation_limit)
+            .min_by_key(|(_, _, queued)| *queued);
+        let (class, i, _) = match starved {
+            Some(head) => *head,
+            None => *heads.last()?,
+        };
+        let (_, info) = self.classes.get_mut(&class)?.remove(i)?;
+
+        self.total -= 1;
+        if let Some(depth) = self.functions.get_mut(&info.0.function) {
+            *depth -= 1;
+            if *depth == 0 {
+                self.functions.remove(&info.0.function);
+            }
+        }
+        Some(info)
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    max_depth: usize,
+    starvation_limit: Duration,
+    policies: Mutex<HashMap<String, FunctionPolicy>>,
+    aliases: Mutex<BTreeMap<String, Alias>>,
+    invoke_limits: Mutex<InvokeLimits>,
+    state: Mutex<State>,
+    // notified every time a request is queued or a worker is asked to shut down
+    available: Condvar,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestQueue {
+    shared: Arc<Shared>,
+}
+
+impl RequestQueue {
+    /// Create a queue holding at most `max_depth` requests. The queue limit and
+    /// the priority class of each function are taken from `functions`.
+    pub fn new(max_depth: usize, starvation_limit: Duration, functions: &BTreeMap<String, FunctionConfig>) -> Self {
+        let queue = RequestQueue {
+            shared: Arc::new(Shared {
+                max_depth,
+                starvation_limit,
+                policies: Default::default(),
+                aliases: Default::default(),
+                invoke_limits: Default::default(),
+                state: Default::default(),
+                available: Condvar::new(),
+            }),
+        };
+        queue.set_functions(functions);
+        queue
+    }
+
+    /// Replace the queue limits and priority classes of all functions with those in `functions`
+    pub fn set_functions(&self, functions: &BTreeMap<String, FunctionConfig>) {
+        let policies = functions.iter()
+            .map(|(name, config)| (name.clone(), FunctionPolicy::new(config)))
+            .collect();
+        *self.shared.policies.lock().unwrap() = policies;
+    }
+
+    /// Set the queue limit and priority class of function `name`
+    pub fn set_function(&self, name: &str, config: &FunctionConfig) {
+        self.shared.policies.lock().unwrap().insert(name.to_string(), FunctionPolicy::new(config));
+    }
+
+    /// Return the maximum number of requests the queue holds
+    pub fn max_depth(&self) -> usize {
+        self.shared.max_depth
+    }
+
+    /// Replace the aliases requests are routed through
+    pub fn set_aliases(&self, aliases: &BTreeMap<String, Alias>) {
+        *self.shared.aliases.lock().unwrap() = aliases.clone();
+    }
+
+    /// Set the limits on chains of invocations
+    pub fn set_invoke_limits(&self, limits: InvokeLimits) {
+        *self.shared.invoke_limits.lock().unwrap() = limits;
+    }
+
+    /// Enqueue a request, or respond with `RequestStatus::Overloaded` if the queue or the
+    /// function's share of it is full and with `RequestStatus::InvokeRejected` if it breaks
+    /// the invoke limits. Return false if the request was not enqueued.
+    /// A request without a trace context starts a new trace.
+    pub fn submit(&self, (mut req, rsp_sender, mut tsps): RequestInfo) -> bool {
+        req.trace = Some(TraceContext::or_root(req.trace.take()));
+        tsps.request.trace = req.trace.clone();
+        if let Some(function) = self.route(&req.function) {
+            req.alias = Some(std::mem::replace(&mut req.function, function));
+            tsps.request.function = req.function.clone();
+            tsps.request.alias = req.alias.clone();
+        }
+
+        let limits = *self.shared.invoke_limits.lock().unwrap();
+        if let Err(e) = limits.check(&req) {
+            warn!("Rejecting request to function {:?} invoked by {:?}: {:?}", req.function, req.ancestry, e);
+            let response = Response { trace: req.trace, ..Response::failed(RequestStatus::InvokeRejected, e) };
+            worker::respond(&rsp_sender, req.async_id, response);
+            return false;
+        }
+        let policy = self.shared.policies.lock().unwrap().get(&req.function).cloned().unwrap_or_default();
+        let class = req.priority.unwrap_or(policy.priority);
+        // the timeout covers the time the request waits in the queue
+        if req.deadline.is_none() {
+            req.deadline = req.timeout.or(policy.timeout).map(|ms| Instant::now() + Duration::from_millis(ms));
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        let function_depth = state.functions.get(&req.function).copied().unwrap_or(0);
+        if state.total >= self.shared.max_depth || policy.limit.map_or(false, |l| function_depth >= l) {
+            drop(state);
+            warn!("Request queue is full, rejecting request to function {:?}", req.function);
+            let response = Response { trace: req.trace, ..Response::new(RequestStatus::Overloaded) };
+            worker::respond(&rsp_sender, req.async_id, response);
+            return false;
+        }
+        state.total += 1;
+        state.functions.insert(req.function.clone(), function_depth + 1);
+        tsps.queue_depth = state.total;
+        // workers kept for invoked requests cannot serve the others, so wake them all
+        let invoked = !req.ancestry.is_empty();
+        state.classes.entry(class).or_default().push_back((Instant::now(), (req, rsp_sender, tsps)));
+        if invoked {
+            self.shared.available.notify_one();
+        } else {
+            self.shared.available.notify_all();
+        }
+        true
+    }
+
+    /// Return the function a request to `function` goes to if `function` is an alias
+    fn route(&self, function: &str) -> Option<String> {
+        self.shared.aliases.lock().unwrap().get(function)?.route().map(String::from)
+    }
+
+    /// Block until there is a request to serve or the calling worker should shut down
+    pub fn recv(&self) -> Message {
+        self.recv_where(false)
+    }
+
+    /// Block until there is a request invoked by a function to serve or the calling worker
+    /// should shut down. Workers kept for such requests serve callees even when every other
+    /// worker is blocked in a synchronous invocation.
+    pub fn recv_invoked(&self) -> Message {
+        self.recv_where(true)
+    }
+
+    fn recv_where(&self, invoked_only: bool) -> Message {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(info) = state.pop(self.shared.starvation_limit, invoked_only) {
+                return Message::Request(info);
+            }
+            if state.shutdown > 0 {
+                state.shutdown -= 1;
+                return Message::Shutdown;
+            }
+            state = self.shared.available.wait(state).unwrap();
+        }
+    }
+
+    /// Ask `workers` workers to shut down once the queue is drained
+    pub fn shutdown(&self, workers: usize) {
+        self.shared.state.lock().unwrap().shutdown += workers;
+        self.shared.available.notify_all();
+    }
+
+    /// Return the number of queued requests
+    pub fn depth(&self) -> usize {
+        self.shared.state.lock().unwrap().total
+    }
+
+    /// Return the number of queued requests to `function`
+    pub fn function_depth(&self, function: &str) -> usize {
+        self.shared.state.lock().unwrap().functions.get(function).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn request_to(function: &str, priority: Option<Priority>) -> (RequestInfo, Receiver<Response>) {
+        let req = Request { function: function.to_string(), priority, ..Default::default() };
+        let (tx, rx) = channel();
+        ((req, tx, Default::default()), rx)
+    }
+
+    fn recv_function(queue: &RequestQueue) -> String {
+        match queue.recv() {
+            Message::Request((req, _, _)) => req.function,
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_queue_limits() {
+        let mut functions = BTreeMap::new();
+        functions.insert("small".to_string(), FunctionConfig { queue_limit: Some(1), ..Default::default() });
+        let queue = RequestQueue::new(3, DEFAULT_STARVATION_LIMIT, &functions);
+
+        let (info, _rx1) = request_to("small", None);
+        assert!(queue.submit(info));
+        // "small" is at its limit
+        let (info, rx) = request_to("small", None);
+        assert!(!queue.submit(info));
+        assert_eq!(rx.recv().unwrap().status, RequestStatus::Overloaded);
+
+        let (info, _rx2) = request_to("big", None);
+        assert!(queue.submit(info));
+        let (info, _rx3) = request_to("big", None);
+        assert!(queue.submit(info));
+        assert_eq!(queue.depth(), 3);
+        assert_eq!(queue.function_depth("big"), 2);
+        // the queue is full
+        let (info, rx) = request_to("big", None);
+        assert!(!queue.submit(info));
+        assert_eq!(rx.recv().unwrap().status, RequestStatus::Overloaded);
+
+        // a worker dequeues the first request
+        match queue.recv() {
+            Message::Request((req, _, tsps)) => {
+                assert_eq!(req.function, "small");
+                assert_eq!(tsps.queue_depth, 1);
+                // the request started a new trace
+                assert!(req.trace.is_some());
+                assert_eq!(tsps.request.trace, req.trace);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(queue.function_depth("small"), 0);
+        let (info, _rx4) = request_to("small", None);
+        assert!(queue.submit(info));
+    }
+
+    #[test]
+    fn test_priority_and_starvation() {
+        let mut functions = BTreeMap::new();
+        functions.insert("grader".to_string(), FunctionConfig { priority: Priority::Low, ..Default::default() });
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, Duration::from_millis(100), &functions);
+
+        let (info, _rx1) = request_to("grader", None);
+        queue.submit(info);
+        let (info, _rx2) = request_to("hello", None);
+        queue.submit(info);
+        let (info, _rx3) = request_to("urgent", Some(Priority::High));
+        queue.submit(info);
+        assert_eq!(recv_function(&queue), "urgent");
+        assert_eq!(recv_function(&queue), "hello");
+
+        // the low priority request has waited too long to be passed over again
+        let (info, _rx4) = request_to("hello", None);
+        queue.submit(info);
+        std::thread::sleep(Duration::from_millis(150));
+        let (info, _rx5) = request_to("urgent", Some(Priority::High));
+        queue.submit(info);
+        assert_eq!(recv_function(&queue), "grader");
+        assert_eq!(recv_function(&queue), "hello");
+        assert_eq!(recv_function(&queue), "urgent");
+
+        queue.shutdown(1);
+        assert!(matches!(queue.recv(), Message::Shutdown));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut functions = BTreeMap::new();
+        functions.insert("slow".to_string(), FunctionConfig { timeout: Some(60_000), ..Default::default() });
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &functions);
+        let recv_request = || match queue.recv() {
+            Message::Request((req, _, _)) => req,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+
+        let (info, _rx1) = request_to("hello", None);
+        queue.submit(info);
+        assert!(recv_request().deadline.is_none());
+
+        // the function's default timeout starts counting at admission
+        let admitted = Instant::now();
+        let (info, _rx2) = request_to("slow", None);
+        queue.submit(info);
+        let req = recv_request();
+        let deadline = req.deadline.unwrap();
+        assert!(deadline >= admitted + Duration::from_secs(60) && deadline <= Instant::now() + Duration::from_secs(60));
+
+        // the request's own timeout overrides it and keeps running while the request waits
+        let (mut info, _rx3) = request_to("slow", None);
+        info.0.timeout = Some(10);
+        queue.submit(info);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(recv_request().time_left(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_reserved_workers_serve_callees() {
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &BTreeMap::new());
+        let pool_size = 4;
+        let reserved = worker::reserved_workers(pool_size);
+        assert_eq!(reserved, 1);
+        // workers whose callers invoke "callee" synchronously and block until it responds
+        let pool: Vec<_> = (0..pool_size).map(|i| {
+            let queue = queue.clone();
+            std::thread::spawn(move || loop {
+                let msg = if i < reserved { queue.recv_invoked() } else { queue.recv() };
+                match msg {
+                    Message::Request((req, rsp_sender, _)) if req.ancestry.is_empty() => {
+                        let (tx, rx) = channel();
+                        let callee = Request { function: "callee".to_string(), ancestry: vec![req.function], ..Default::default() };
+                        queue.submit((callee, tx, Default::default()));
+                        let response = rx.recv_timeout(Duration::from_secs(5))
+                            .unwrap_or_else(|_| Response::new(RequestStatus::Timeout));
+                        let _ = rsp_sender.send(response);
+                    }
+                    Message::Request((_, rsp_sender, _)) => {
+                        let _ = rsp_sender.send(Response::completed("{}"));
+                    }
+                    Message::Shutdown => return,
+                    msg => panic!("unexpected message {:?}", msg),
+                }
+            })
+        }).collect();
+
+        // more callers than the workers that serve them, which all end up waiting for callees
+        let receivers: Vec<_> = (0..2 * pool_size).map(|_| {
+            let (info, rx) = request_to("caller", None);
+            assert!(queue.submit(info));
+            rx
+        }).collect();
+        for rx in receivers {
+            assert_eq!(rx.recv().unwrap().status, RequestStatus::SentToVM);
+        }
+
+        queue.shutdown(pool_size);
+        for worker in pool {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_invoke_limits() {
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &BTreeMap::new());
+        queue.set_invoke_limits(InvokeLimits { max_depth: 2, allow_cycles: false });
+        let invoked_by = |function: &str, ancestry: &[&str]| {
+            let (mut info, rx) = request_to(function, None);
+            info.0.ancestry = ancestry.iter().map(|f| f.to_string()).collect();
+            (info, rx)
+        };
+
+        let (info, _rx1) = invoked_by("c", &["a", "b"]);
+        assert!(queue.submit(info));
+
+        let (info, rx) = invoked_by("d", &["a", "b", "c"]);
+        assert!(!queue.submit(info));
+        let response = rx.recv().unwrap();
+        assert_eq!(response.status, RequestStatus::InvokeRejected);
+        assert_eq!(response.error, Some(RequestError::InvokeDepthExceeded(2)));
+
+        let (info, rx) = invoked_by("a", &["a", "b"]);
+        assert!(!queue.submit(info));
+        let chain = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+        assert_eq!(rx.recv().unwrap().error, Some(RequestError::InvokeCycle(chain)));
+
+        queue.set_invoke_limits(InvokeLimits { max_depth: 2, allow_cycles: true });
+        let (info, _rx2) = invoked_by("a", &["a", "b"]);
+        assert!(queue.submit(info));
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &BTreeMap::new());
+        let mut aliases = BTreeMap::new();
+        aliases.insert("grader@canary".to_string(), serde_yaml::from_str("{grader@v2: 1}").unwrap());
+        queue.set_aliases(&aliases);
+
+        let (info, _rx) = request_to("grader@canary", None);
+        assert!(queue.submit(info));
+        let (info, _rx) = request_to("grader@v1", None);
+        assert!(queue.submit(info));
+        match queue.recv() {
+            Message::Request((req, _, tsps)) => {
+                assert_eq!(req.function, "grader@v2");
+                assert_eq!(req.alias.as_deref(), Some("grader@canary"));
+                assert_eq!(tsps.request.function, "grader@v2");
+                assert_eq!(tsps.request.alias.as_deref(), Some("grader@canary"));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(recv_function(&queue), "grader@v1");
+    }
+}
@@ -0,0 +1,273 @@
+//! Functions deployed while the controller runs
+//!
+//! Besides the functions in the controller YAML, functions can be registered and unregistered
+//! while `multivm` runs through the HTTP gateway (see `gateway::http`). The registry checks a
+//! function's config, resolves its images against the directories in the YAML as it does for
+//! the YAML's functions and checks that they exist and stay in those directories. It then hands the resource manager, as a
+//! `Message::Reload`, and the request queue the functions of the YAML together with the
+//! registered ones. Registered functions are written as JSON to the registry file and
+//! registered again when `multivm` restarts.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use crate::configs::{FunctionConfig, ResourceManagerConfig};
+use crate::message::Message;
+use crate::queue::RequestQueue;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the config is inconsistent or its images do not exist
+    Invalid(String),
+    /// the name is taken by a function, a workflow or an alias of the controller YAML
+    Conflict(String),
+    NotFound,
+    /// the registry file could not be written
+    Persist(std::io::Error),
+}
+
+#[derive(Debug)]
+struct State {
+    // configuration loaded from the controller YAML
+    config: ResourceManagerConfig,
+    // registered functions as they were submitted, with images relative to the YAML's directories
+    registered: BTreeMap<String, FunctionConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Registry {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    request_queue: RequestQueue,
+    manager_sender: Sender<Message>,
+}
+
+impl Registry {
+    /// Register the functions persisted in the registry file `path`, if it exists, on top of
+    /// those of `config`. Persisted functions that are no longer valid are skipped.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        config: ResourceManagerConfig,
+        request_queue: RequestQueue,
+        manager_sender: Sender<Message>,
+    ) -> std::io::Result<Self> {
+        let registered: BTreeMap<String, FunctionConfig> = match fs::read(path.as_ref()) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let registry = Registry {
+            path: path.as_ref().to_path_buf(),
+            state: Arc::new(Mutex::new(State { config, registered })),
+            request_queue,
+            manager_sender,
+        };
+        registry.apply(&registry.state.lock().unwrap());
+        Ok(registry)
+    }
+
+    /// Register `function` under `name`, replacing the function registered under that name if
+    /// any, in which case the function returns true. VMs of the replaced function are retired
+    /// like on a reload of the configuration (see `ResourceManager::reload`).
+    pub fn register(&self, name: &str, function: FunctionConfig) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.config.declares(name) {
+            return Err(Error::Conflict(format!("{:?} is declared in the controller configuration", name)));
+        }
+        resolve(&state.config, name, &function).map_err(Error::Invalid)?;
+
+        let mut registered = state.registered.clone();
+        let replaced = registered.insert(name.to_string(), function).is_some();
+        self.persist(&registered)?;
+        state.registered = registered;
+        info!("Registered function {:?}", name);
+        self.apply(&state);
+        Ok(replaced)
+    }
+
+    /// Unregister function `name`. Its VMs are retired once they finish serving requests.
+    pub fn unregister(&self, name: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.registered.contains_key(name) {
+            if state.config.declares(name) {
+                return Err(Error::Conflict(format!("{:?} is declared in the controller configuration", name)));
+            }
+            return Err(Error::NotFound);
+        }
+
+        let mut registered = state.registered.clone();
+        registered.remove(name);
+        self.persist(&registered)?;
+        state.registered = registered;
+        info!("Unregistered function {:?}", name);
+        self.apply(&state);
+        Ok(())
+    }
+
+    /// Return the registered functions as they were submitted
+    pub fn functions(&self) -> BTreeMap<String, FunctionConfig> {
+        self.state.lock().unwrap().registered.clone()
+    }
+
+    /// Replace the configuration loaded from the controller YAML, keeping the registered
+    /// functions whose names it does not take
+    pub fn reload(&self, config: ResourceManagerConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        self.apply(&state);
+    }
+
+    // Hand the functions of the YAML and the registered ones to the request queue
+    // and the resource manager
+    fn apply(&self, state: &State) {
+        let mut config = state.config.clone();
+        for (name, function) in &state.registered {
+            if config.declares(name) {
+                error!("Registered function {:?} is declared in the controller configuration, skipping it", name);
+                continue;
+            }
+            match resolve(&state.config, name, function) {
+                Ok(function) => {
+                    config.functions.insert(name.clone(), function);
+                }
+                Err(e) => error!("Skipping registered function {:?}: {}", name, e),
+            }
+        }
+        self.request_queue.set_functions(&config.functions);
+        if self.manager_sender.send(Message::Reload(config)).is_err() {
+            error!("Resource manager is gone, registered functions are not served");
+        }
+    }
+
+    // Write the registered functions to a temporary file then move it over the registry file
+    // so that the registry file is never left half-written
+    fn persist(&self, registered: &BTreeMap<String, FunctionConfig>) -> Result<(), Error> {
+        let tmp = self.path.with_extension("tmp");
+        let bytes = serde_json::to_vec_pretty(registered).map_err(|e| Error::Persist(e.into()))?;
+        fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, &self.path)).map_err(Error::Persist)
+    }
+}
+
+/// Return `function` with its images resolved against the directories of `config`, or an
+/// error if it is inconsistent or its images do not exist or are outside those directories
+fn resolve(config: &ResourceManagerConfig, name: &str, function: &FunctionConfig) -> Result<FunctionConfig, String> {
+    config.check_function(name, function)?;
+    check_relative(&function.runtimefs, "runtimefs")?;
+    if let Some(appfs) = function.appfs.as_ref() {
+        check_relative(appfs, "appfs")?;
+    }
+    if let Some(load_dir) = function.load_dir.as_ref() {
+        for dir in load_dir.split(',') {
+            check_relative(dir, "snapshot directory")?;
+        }
+    }
+
+    let mut function = function.clone();
+    config.build_full_path(&mut function);
+    check_within(&function.runtimefs, &config.get_runtimefs_base(), "runtimefs")?;
+    if !Path::new(&function.runtimefs).is_file() {
+        return Err(format!("runtimefs {:?} is not a file", function.runtimefs));
+    }
+    if let (Some(appfs), Some(base)) = (function.appfs.as_ref(), config.get_appfs_base()) {
+        check_within(appfs, &base, "appfs")?;
+        if !Path::new(appfs).is_file() {
+            return Err(format!("appfs {:?} is not a file", appfs));
+        }
+    }
+    if let (Some(load_dir), Some(base)) = (function.load_dir.as_ref(), config.get_snapshot_base()) {
+        for dir in load_dir.split(',') {
+            check_within(dir, &base, "snapshot directory")?;
+            if !Path::new(dir).is_dir() {
+                return Err(format!("snapshot directory {:?} is not a directory", dir));
+            }
+        }
+    }
+    Ok(function)
+}
+
+// Images of registered functions come from clients, they must be relative to their
+// directory and cannot climb out of it
+fn check_relative(path: &str, what: &str) -> Result<(), String> {
+    let relative = Path::new(path);
+    if relative.is_absolute() || relative.components().any(|c| c == Component::ParentDir) {
+        return Err(format!("{} {:?} must be a path within its directory, without `..`", what, path));
+    }
+    Ok(())
+}
+
+// Check that `path` exists and, symbolic links followed, is under directory `base`
+fn check_within(path: &str, base: &str, what: &str) -> Result<(), String> {
+    let resolved = fs::canonicalize(path).map_err(|_| format!("{} {:?} does not exist", what, path))?;
+    let base = fs::canonicalize(base).map_err(|_| format!("{} base {:?} does not exist", what, base))?;
+    if !resolved.starts_with(&base) {
+        return Err(format!("{} {:?} is outside {:?}", what, path, base));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT};
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn reloaded_functions(manager: &Receiver<Message>) -> Vec<String> {
+        match manager.try_recv() {
+            Ok(Message::Reload(config)) => config.functions.keys().cloned().collect(),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("python3.ext4"), b"").unwrap();
+        let mut config = ResourceManagerConfig {
+            runtimefs_dir: crate::convert_fs_path_to_url(dir.path().to_str().unwrap()).unwrap(),
+            kernel_path: crate::convert_fs_path_to_url(dir.path().join("python3.ext4").to_str().unwrap()).unwrap(),
+            ..Default::default()
+        };
+        config.functions.insert("static".to_string(), FunctionConfig::default());
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &BTreeMap::new());
+        let (manager_sender, manager) = channel();
+        let path = dir.path().join("functions.json");
+
+        let registry = Registry::open(&path, config.clone(), queue.clone(), manager_sender.clone()).unwrap();
+        assert_eq!(reloaded_functions(&manager), vec!["static"]);
+        let hello = FunctionConfig { runtimefs: "python3.ext4".to_string(), ..Default::default() };
+        assert!(!registry.register("hello", hello.clone()).unwrap());
+        assert_eq!(reloaded_functions(&manager), vec!["hello", "static"]);
+        assert!(registry.register("hello", hello.clone()).unwrap());
+        reloaded_functions(&manager);
+
+        let missing = FunctionConfig { runtimefs: "nodejs.ext4".to_string(), ..Default::default() };
+        assert!(matches!(registry.register("missing", missing), Err(Error::Invalid(_))));
+        // images and names cannot escape their directories
+        let other = tempfile::tempdir().unwrap();
+        let outside = other.path().join("outside.ext4");
+        fs::write(&outside, b"").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.path().join("link.ext4")).unwrap();
+        for runtimefs in [outside.to_str().unwrap(), "../outside.ext4", "link.ext4"] {
+            let escaping = FunctionConfig { runtimefs: runtimefs.to_string(), ..Default::default() };
+            assert!(matches!(registry.register("escaping", escaping), Err(Error::Invalid(_))));
+        }
+        for name in ["..", "a/b", ""] {
+            assert!(matches!(registry.register(name, hello.clone()), Err(Error::Invalid(_))));
+        }
+        assert!(matches!(registry.register("static", hello), Err(Error::Conflict(_))));
+        assert!(matches!(registry.unregister("static"), Err(Error::Conflict(_))));
+        assert!(manager.try_recv().is_err());
+
+        // registered functions survive a restart
+        let registry = Registry::open(&path, config, queue, manager_sender).unwrap();
+        assert_eq!(reloaded_functions(&manager), vec!["hello", "static"]);
+        registry.unregister("hello").unwrap();
+        assert_eq!(reloaded_functions(&manager), vec!["static"]);
+        assert!(matches!(registry.unregister("hello"), Err(Error::NotFound)));
+        assert!(registry.functions().is_empty());
+    }
+}
@@ -1,22 +1,212 @@
-use std::io::{Error, ErrorKind, Write, Read};
+use std::time::{Duration, Instant};
+
+use labeled::dclabel::DCLabel;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+use crate::resource_manager;
+use crate::trace::TraceContext;
+use crate::vm;
+use crate::workflow::StepResult;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum RequestStatus {
     Dropped,
     FunctionNotExist,
     ResourceExhausted,
     LaunchFailed,
-    SentToVM(String),
+    /// The function ran, its output is in `Response::output`
+    SentToVM,
+    /// The VM failed while processing the request
+    ExecutionFailed,
+    /// The function did not respond before the request's deadline
+    Timeout,
+    /// The request queue, or the function's share of it, is full, see `queue`,
+    /// or the function's `concurrency_limit` VMs are all busy
+    Overloaded,
+    /// Asynchronous request accepted under the given ID, see `results`
+    Accepted(String),
+    /// The request could not be parsed or violates the wire protocol
+    BadRequest(String),
+    /// A function invoked another one too deep down a chain of invocations, or invoked a
+    /// function already on the chain, see `queue::InvokeLimits`
+    InvokeRejected,
+}
+
+/// Priority class of a request, see `queue`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("invalid priority {:?}", s)),
+        }
+    }
+}
+
+/// The cause of a failed request, one variant for every `resource_manager::Error`
+/// and `vm::Error`. I/O and decoding errors are carried as their messages.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RequestError {
+    // resource_manager::Error
+    LowMemory(usize),
+    LowCpu(usize),
+    NoEvictCandidate,
+    InsufficientEvict,
+    NoIdleVm,
+    FunctionNotExist,
+    ConcurrencyLimit(usize),
+    TenantQuota(String),
+    // vm::Error
+    ProcessSpawn(String),
+    Rpc(String),
+    VsockListen(String),
+    VsockWrite(String),
+    VsockRead(String),
+    HttpReq(String),
+    AuthTokenInvalid,
+    AuthTokenNotExist,
+    KernelNotExist,
+    RootfsNotExist,
+    AppfsNotExist,
+    LoadDirNotExist,
+    IOError(String),
+    Timeout,
+    Cgroup(String),
+    // invoke syscall
+    /// the chain of invocations is longer than the given maximum depth
+    InvokeDepthExceeded(usize),
+    /// the chain of invocations, from the outermost caller to the callee, contains a cycle
+    InvokeCycle(Vec<String>),
 }
-                
+
+impl From<resource_manager::Error> for RequestError {
+    fn from(e: resource_manager::Error) -> Self {
+        match e {
+            resource_manager::Error::LowMemory(mem) => RequestError::LowMemory(mem),
+            resource_manager::Error::LowCpu(vcpus) => RequestError::LowCpu(vcpus),
+            resource_manager::Error::NoEvictCandidate => RequestError::NoEvictCandidate,
+            resource_manager::Error::InsufficientEvict => RequestError::InsufficientEvict,
+            resource_manager::Error::NoIdleVm => RequestError::NoIdleVm,
+            resource_manager::Error::FunctionNotExist => RequestError::FunctionNotExist,
+            resource_manager::Error::ConcurrencyLimit(limit) => RequestError::ConcurrencyLimit(limit),
+            resource_manager::Error::TenantQuota(tenant) => RequestError::TenantQuota(tenant),
+        }
+    }
+}
+
+impl From<vm::Error> for RequestError {
+    fn from(e: vm::Error) -> Self {
+        match e {
+            vm::Error::ProcessSpawn(e) => RequestError::ProcessSpawn(e.to_string()),
+            vm::Error::Rpc(e) => RequestError::Rpc(e.to_string()),
+            vm::Error::VsockListen(e) => RequestError::VsockListen(e.to_string()),
+            vm::Error::VsockWrite(e) => RequestError::VsockWrite(e.to_string()),
+            vm::Error::VsockRead(e) => RequestError::VsockRead(e.to_string()),
+            vm::Error::HttpReq(e) => RequestError::HttpReq(e.to_string()),
+            vm::Error::AuthTokenInvalid => RequestError::AuthTokenInvalid,
+            vm::Error::AuthTokenNotExist => RequestError::AuthTokenNotExist,
+            vm::Error::KernelNotExist => RequestError::KernelNotExist,
+            vm::Error::RootfsNotExist => RequestError::RootfsNotExist,
+            vm::Error::AppfsNotExist => RequestError::AppfsNotExist,
+            vm::Error::LoadDirNotExist => RequestError::LoadDirNotExist,
+            vm::Error::IOError(e) => RequestError::IOError(e.to_string()),
+            vm::Error::Timeout => RequestError::Timeout,
+            vm::Error::Cgroup(e) => RequestError::Cgroup(e.to_string()),
+        }
+    }
+}
+
+/// Where a request spent its time, in ns. See `metrics::RequestTimestamps`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Timings {
+    /// from arriving at the gateway (or the invoking VM) to being picked up by a worker
+    pub queueing: u64,
+    /// allocating an idle VM or the resources for a new one
+    pub allocation: u64,
+    /// launching a new VM, 0 for warm starts
+    pub launch: u64,
+    /// running the function
+    pub execution: u64,
+    /// whether a new VM was launched for the request
+    pub cold_start: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     pub status: RequestStatus,
+    /// the function's output parsed as JSON. Output that is not valid JSON
+    /// is kept as a JSON string.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub output: Value,
+    /// why the request failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RequestError>,
+    /// absent if the request never reached a worker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// results of the function steps of a workflow, see `workflow`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepResult>,
+    /// correlation ID copied from the `Request` this responds to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// trace context of the request, to look its spans up in the exported traces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+    /// the function's current label when it responded, None if it did not run.
+    /// A function invoking it synchronously is raised to this label.
+    #[serde(skip)]
+    pub label: Option<DCLabel>,
 }
 
 impl Response {
+    pub fn new(status: RequestStatus) -> Self {
+        Response {
+            status,
+            output: Value::Null,
+            error: None,
+            timings: None,
+            steps: Vec::new(),
+            id: None,
+            trace: None,
+            label: None,
+        }
+    }
+
+    /// Return the response of a request that failed because of `error`
+    pub fn failed<E: Into<RequestError>>(status: RequestStatus, error: E) -> Self {
+        Response {
+            error: Some(error.into()),
+            ..Response::new(status)
+        }
+    }
+
+    /// Return the response of a completed request carrying the guest's raw `output`
+    pub fn completed(output: &str) -> Self {
+        Response {
+            output: serde_json::from_str(output).unwrap_or_else(|_| Value::String(output.to_string())),
+            ..Response::new(RequestStatus::SentToVM)
+        }
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         serde_json::to_vec(&self).unwrap()
     }
@@ -26,6 +216,45 @@ impl Response {
 pub struct Request {
     pub function: String,
     pub payload: Value,
+    /// correlation ID chosen by the client. Over a TCP connection, requests carrying an ID are
+    /// pipelined and their responses, tagged with the same ID, are returned in the order
+    /// they complete. Requests without an ID are served one at a time, in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// maximum time in ms the function may take to respond,
+    /// overrides the function's default `timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// priority class, overrides the function's default `priority`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// trace this request belongs to and its span, see `trace`. A request without one
+    /// starts a new trace; a client may set just `trace_id` to join a trace of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+    /// alias the request was sent to, `function` is then the version it was routed to.
+    /// See `alias`.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// respond right away with the request's ID and keep the response
+    /// in the result store instead
+    #[serde(default, rename = "async", skip_serializing_if = "std::ops::Not::not")]
+    pub is_async: bool,
+    /// ID assigned by the gateway to an asynchronous request
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub async_id: Option<String>,
+    /// current label of the function invoking this one, None for requests from clients.
+    /// The callee's VM starts at least at this label.
+    #[serde(skip)]
+    pub label: Option<DCLabel>,
+    /// functions that invoked this one, from the outermost caller to the direct caller.
+    /// Empty for requests from clients and workflows.
+    #[serde(skip)]
+    pub ancestry: Vec<String>,
+    /// time by which the function must respond, set when the request is admitted from
+    /// `timeout` or else the function's default `timeout`. None if neither is set.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
 }
 
 impl Request {
@@ -38,49 +267,10 @@ impl Request {
         return self.payload.to_string();
     }
 
-}
-
-/// Given a [u8], parse it to a Requst value
-pub fn parse_u8_request(buf: Vec<u8>) -> Result<Request, serde_json::Error> {
-    serde_json::from_slice(&buf)
-}
-
-/// Write a [u8] array to channel.
-/// Channel is anything that implements the std::io::Read trait. In practice,
-/// this includes TcpStream and stdin pipe for Firerunner processes.
-/// `write_u8` will first write 4 bytes ([u8; 4]) into the channel. These 4 bytes
-/// encodes the size of the buf in big endian. It then writes the buf into the
-/// channel. 
-/// On success, `write_u8` returns Ok(()).
-/// If either one of the `write_all()` calls fails, `write_u8` returns
-/// the corresponding std::io::Error.
-pub fn write_u8(buf: &[u8], channel: &mut std::net::TcpStream) ->  std::io::Result<()> {
-    let size = buf.len().to_be_bytes();
-    channel.write_all(&size)?;
-    channel.write_all(buf)?;
-    return Ok(());
-}
-
-/// Read a [u8] array from a channel.
-/// Channel is anything that implements the std::io::Read trait. In practice,
-/// this includes TcpStream and stdin pipe for Firerunner processes.
-/// `read_u8` will first read 4 bytes from the channel. These 4 bytes encodes
-/// the size of the payload in big endian. After deciding the size of the
-/// payload, `read_u8` then read another [u8] buf of length `size` and return
-/// Ok(buf).
-/// If `size` is zero, `read_u8` will try again
-/// If either one of the `read_exact()` calls fails, `read_u8` returns
-/// the corresponding std::io::Error.
-pub fn read_u8(channel: &mut std::net::TcpStream) -> std::io::Result<Vec<u8>>{
-    let mut buf = [0;8];
-    channel.read_exact(&mut buf)?;
-    let size = u64::from_be_bytes(buf);
-
-    if size > 0 {
-        let mut buf = vec![0; size as usize];
-        channel.read_exact(&mut buf)?;
-        return Ok(buf);
+    /// Return the time left until the deadline, zero once it has passed
+    /// and None if the request has no deadline
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
     }
 
-    Err(Error::new(ErrorKind::Other, "Empty payload"))
 }
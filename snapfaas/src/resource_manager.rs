@@ -1,40 +1,60 @@
 use std::result::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::{error, debug};
 
 use crate::configs::{ResourceManagerConfig, FunctionConfig};
+use crate::eviction::{Candidate, EvictionPolicy, FunctionUsage};
+use crate::prediction::ArrivalHistory;
+use crate::tenant::TenantUsage;
 use crate::vm::Vm;
 use crate::message::Message;
 
 #[derive(Debug)]
 pub enum Error {
     LowMemory(usize),
+    LowCpu(usize),
     NoEvictCandidate,
     InsufficientEvict,
     NoIdleVm,
     FunctionNotExist,
+    ConcurrencyLimit(usize),
+    /// the function's tenant is at its memory or VM cap
+    TenantQuota(String),
 }
 
+// how often idle vms are checked against their function's `keep_alive` and predicted demand
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct VmList {
     num_vms: AtomicUsize,
-    list: Mutex<Vec<Vm>>,
+    list: Mutex<Vec<(Instant, Vm)>>, // vms with the time they became idle, oldest first
 }
 
 #[derive(Debug)]
 pub struct ResourceManager {
     config: ResourceManagerConfig,
     idle: HashMap<String, VmList>, // from function name to a vector of VMs
+    usage: HashMap<String, FunctionUsage>, // from function name to the use of its VMs
+    busy: HashMap<String, usize>, // from function name to the number of its VMs serving requests
+    prewarming: HashMap<String, usize>, // from function name to the number of its VMs being pre-warmed
+    arrivals: HashMap<String, ArrivalHistory>, // from function name to the history of its requests
+    predicted: HashMap<String, usize>, // from function name to the number of idle VMs predicted demand needs
+    eviction: Box<dyn EvictionPolicy>,
     receiver: Receiver<Message>,
     pub total_num_vms: usize, // total number of vms ever created
     total_mem: usize,
     pub free_mem: usize,
+    vcpu_limit: Option<usize>, // number of vcpus vms may add up to, unlimited if None
+    used_vcpus: usize, // number of vcpus of allocated vms
+    tenants: HashMap<String, TenantUsage>, // from tenant name to the resources its vms hold
 }
 
 impl ResourceManager {
@@ -48,14 +68,24 @@ impl ResourceManager {
         // set default total memory to free memory on the machine
         let total_mem = crate::get_machine_memory();
         let (sender, receiver) = mpsc::channel();
-        
+        let eviction = config.eviction.policy();
+
         (ResourceManager {
             config,
             idle,
+            usage: HashMap::new(),
+            busy: HashMap::new(),
+            prewarming: HashMap::new(),
+            arrivals: HashMap::new(),
+            predicted: HashMap::new(),
+            eviction,
             receiver,
             total_num_vms: 0,
             total_mem,
             free_mem: total_mem,
+            vcpu_limit: None,
+            used_vcpus: 0,
+            tenants: HashMap::new(),
         },
         sender)
     }
@@ -76,15 +106,52 @@ impl ResourceManager {
             error!("Total memory cannot be 0. Total memory remains {}.", self.total_mem);
             return;
         }
+        let reserved: usize = self.config.functions.values()
+            .map(|f| f.reserved_concurrency * f.memory)
+            .chain(self.config.tenants.values().map(|t| t.min_memory))
+            .sum();
+        if reserved > mem {
+            error!("Functions and tenants reserve {} MB, more than the total memory {}. \
+                Some reservations cannot be honored.", reserved, mem);
+        }
         self.total_mem = mem;
         self.free_mem = mem;
     }
+
+    /// Let the vcpus of allocated vms add up to at most `ratio` times `cpus`, the number of CPUs
+    /// of the machine vms run on. Like memory, vcpus of idle vms are evicted when they run out.
+    /// This function should only be called once before resource manager kicks off.
+    pub fn set_cpu_overcommit(&mut self, cpus: usize, ratio: f64) {
+        let limit = (cpus as f64 * ratio).floor() as usize;
+        if limit == 0 {
+            error!("vCPU limit cannot be 0. vCPUs remain unlimited.");
+            return;
+        }
+        let reserved: usize = self.config.functions.values()
+            .map(|f| f.reserved_concurrency * f.vcpus as usize)
+            .sum();
+        if reserved > limit {
+            error!("Functions reserve {} vCPUs, more than the limit {}. \
+                Some reservations cannot be honored.", reserved, limit);
+        }
+        self.vcpu_limit = Some(limit);
+    }
+
+    pub fn vcpu_limit(&self) -> Option<usize> {
+        self.vcpu_limit
+    }
     
     /// Kicks off the single thread resource manager
     pub fn run(mut self) -> JoinHandle<()> {
         std::thread::spawn(move || {
+            let mut last_reap = Instant::now();
             loop {
-                match self.receiver.recv() {
+                if last_reap.elapsed() >= REAP_INTERVAL {
+                    self.reap_idle();
+                    self.predict();
+                    last_reap = Instant::now();
+                }
+                match self.receiver.recv_timeout(REAP_INTERVAL) {
                     Ok(msg) => {
                         match msg {
                             Message::GetVm(function, vm_sender) => {
@@ -96,12 +163,25 @@ impl ResourceManager {
                             Message::DeleteVm(vm) => {
                                 self.delete(vm);
                             }
+                            Message::GetPrewarmVm(vm_sender) => {
+                                let _ = vm_sender.send(self.prewarm_vm());
+                            }
+                            Message::Prewarmed(vm) => {
+                                self.prewarmed(vm, true);
+                            }
+                            Message::PrewarmFailed(vm) => {
+                                self.prewarmed(vm, false);
+                            }
+                            Message::Reload(config) => {
+                                self.reload(config);
+                            }
                             Message::Shutdown => {
                                 return;
                             }
                             _ => (),
                         }
                     }
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
                     Err(e) => {
                         panic!("ResourceManager cannot read requests: {:?}", e);
                     }
@@ -111,26 +191,42 @@ impl ResourceManager {
     }
 
     // Try to acquire an idle VM, otherwise try to allocate a new unlaunched VM.
-    // If there's not enough resources on the machine to
+    // If there's not enough memory or vcpus on the machine to
     // allocate a new Vm, it will try to evict an idle Vm from another
     // function's idle list, and then allocate a new unlaunched VM.
+    // Fail with Error::ConcurrencyLimit if the function's concurrency limit
+    // of VMs are already serving requests.
     fn acquire_vm(
         &mut self,
         function_name: &str,
     )-> Result<Vm, Error> {
-        let func_memory = self.get_function_config(function_name)?.memory;
+        let function_config = self.get_function_config(function_name)?;
+        let (func_memory, func_vcpus) = (function_config.memory, function_config.vcpus as usize);
+        let limit = function_config.concurrency_limit;
+        self.record_arrival(function_name);
+        if self.busy.get(function_name).copied().unwrap_or(0) >= limit {
+            return Err(Error::ConcurrencyLimit(limit));
+        }
 
-        self.get_idle_vm(function_name)
+        let vm = self.get_idle_vm(function_name)
             .or_else(|e| {
                 match e {
                    // No Idle vm for this function. Try to allocate a new vm.
                     Error::NoIdleVm => {
                         self.allocate(function_name)
                     },
-                    // Not enough free memory to allocate. Try eviction
-                    Error::LowMemory(_) => {
-                        if self.evict(func_memory) {
+                    // Just return all other errors
+                    _ => Err(e)
+                }
+            })
+            .or_else(|e| {
+                match e {
+                    // Not enough free memory or vcpus, or the tenant is at its cap. Try eviction
+                    Error::LowMemory(_) | Error::LowCpu(_) | Error::TenantQuota(_) => {
+                        if self.evict(function_name, func_memory, func_vcpus) {
                             self.allocate(function_name)
+                        } else if let Error::TenantQuota(_) = e {
+                            Err(e)
                         } else {
                             Err(Error::InsufficientEvict)
                         }
@@ -138,7 +234,10 @@ impl ResourceManager {
                     // Just return all other errors
                     _ => Err(e)
                 }
-            })
+            })?;
+        self.usage.entry(function_name.to_string()).or_default().record_use();
+        *self.busy.entry(function_name.to_string()).or_default() += 1;
+        Ok(vm)
     }
 
     // Try to find an idle vm from the function's idle list
@@ -149,13 +248,166 @@ impl ResourceManager {
         Err(Error::FunctionNotExist)
     }
 
-    // Push the vm onto its function's idle list
-    fn release(&self, vm: Vm) {
-        self.idle.get(&vm.function_name()).unwrap().push(vm); // unwrap should always work
+    // Push the vm onto its function's idle list, or delete it if the list
+    // already holds the function's `max_idle` vms
+    fn release(&mut self, mut vm: Vm) {
+        self.record_boot(&mut vm);
+        self.finish(&vm);
+        self.keep_idle(vm);
+    }
+
+    // Vms of functions removed, changed or moved to another tenant by a reload are deleted
+    fn keep_idle(&mut self, vm: Vm) {
+        let function_name = vm.function_name();
+        let config = match self.config.functions.get(&function_name) {
+            Some(config) if config.same_vm(vm.function_config()) && config.tenant == vm.function_config().tenant => config,
+            _ => {
+                debug!("Function {:?} was reloaded, deleting its old VM {}", function_name, vm.id());
+                self.free(vm);
+                return;
+            }
+        };
+        let idle_list = self.idle.get(&function_name).unwrap(); // unwrap should always work
+        if config.max_idle.map_or(false, |max| idle_list.len() >= max) {
+            debug!("Function {:?} has enough idle VMs, deleting VM {}", function_name, vm.id());
+            self.free(vm);
+        } else {
+            idle_list.push(vm);
+        }
+    }
+
+    // Replace the configuration. Functions added get an idle list. Idle vms of functions
+    // removed, whose vms would be launched differently or that moved to another tenant, are
+    // deleted right away and their vms serving requests once they are released.
+    fn reload(&mut self, config: ResourceManagerConfig) {
+        let mut old_vms = Vec::new();
+        for (name, idle_list) in &self.idle {
+            let current = match (self.config.functions.get(name), config.functions.get(name)) {
+                (Some(old), Some(new)) => old.same_vm(new) && old.tenant == new.tenant,
+                _ => false,
+            };
+            if !current {
+                old_vms.extend(idle_list.pop_expired(Duration::from_secs(0), 0));
+            }
+        }
+        for vm in old_vms {
+            debug!("Function {:?} was reloaded, deleting its old VM {}", vm.function_name(), vm.id());
+            self.free(vm);
+        }
+        self.idle.retain(|name, _| config.functions.contains_key(name));
+        for name in config.functions.keys() {
+            self.idle.entry(name.clone()).or_insert_with(VmList::new);
+        }
+        self.arrivals.retain(|name, _| config.functions.contains_key(name));
+        self.predicted.retain(|name, _| config.functions.contains_key(name));
+        self.eviction = config.eviction.policy();
+        self.config = config;
+        debug!("ResourceManager config reloaded: {:?}", self.config);
+    }
+
+    // Shut down vms idle for longer than their function's `keep_alive`,
+    // keeping the function's `min_idle` vms
+    fn reap_idle(&mut self) {
+        let mut expired = Vec::new();
+        for (name, config) in &self.config.functions {
+            if let (Some(keep_alive), Some(idle_list)) = (config.keep_alive, self.idle.get(name)) {
+                expired.extend(idle_list.pop_expired(Duration::from_millis(keep_alive), config.min_idle));
+            }
+        }
+        for vm in expired {
+            debug!("VM {} of function {:?} idle for too long, shutting it down", vm.id(), vm.function_name());
+            self.free(vm);
+        }
+    }
+
+    // Record a request to the function for predicting its demand
+    fn record_arrival(&mut self, function_name: &str) {
+        if let Some(config) = self.config.prediction.as_ref() {
+            self.arrivals.entry(function_name.to_string())
+                .or_insert_with(|| ArrivalHistory::new(config))
+                .record(unix_now(), config);
+        }
+    }
+
+    // Update the number of idle vms each function is predicted to need, one while a request
+    // is expected and none otherwise. Idle vms beyond that number and the function's `min_idle`
+    // are shut down once idle for longer than the function's `keep_alive` or, without one,
+    // than a histogram bin, so that those a burst just released may serve the next one.
+    fn predict(&mut self) {
+        let config = match self.config.prediction.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        let now = unix_now();
+        let mut retired = Vec::new();
+        for (name, history) in &self.arrivals {
+            let demand = if history.should_be_warm(now, config) { 1 } else { 0 };
+            if demand > 0 {
+                self.predicted.insert(name.clone(), demand);
+            } else {
+                self.predicted.remove(name);
+            }
+            if let (Some(function), Some(idle_list)) = (self.config.functions.get(name), self.idle.get(name)) {
+                let idle_for = Duration::from_millis(function.keep_alive.unwrap_or(config.bin_width));
+                retired.extend(idle_list.pop_expired(idle_for, function.min_idle.max(demand)));
+            }
+        }
+        for vm in retired {
+            debug!("No request to function {:?} expected, retiring VM {}", vm.function_name(), vm.id());
+            self.free(vm);
+        }
     }
 
-    fn delete(&mut self, vm:Vm) {
+    // Allocate an unlaunched vm for the first function whose idle vms, counting those being
+    // pre-warmed, are fewer than its `min_idle` or than predicted demand needs. Pre-warming
+    // only uses available memory, it never evicts.
+    fn prewarm_vm(&mut self) -> Option<Vm> {
+        let function_name = self.config.functions.iter()
+            .find(|(name, config)| {
+                let idle = self.idle.get(*name).map_or(0, VmList::len);
+                let prewarming = self.prewarming.get(*name).copied().unwrap_or(0);
+                let target = config.min_idle.max(self.predicted.get(*name).copied().unwrap_or(0));
+                let (tenant_mem, tenant_vms) = self.tenant_room(name);
+                idle + prewarming < target
+                    && self.available_mem(name) >= config.memory
+                    && self.available_vcpus(name) >= config.vcpus as usize
+                    && tenant_mem >= config.memory
+                    && tenant_vms > 0
+            })
+            .map(|(name, _)| name.clone())?;
+        let vm = self.allocate(&function_name).ok()?;
+        debug!("Pre-warming VM {} of function {:?}", vm.id(), function_name);
+        *self.prewarming.entry(function_name).or_default() += 1;
+        Some(vm)
+    }
+
+    // Put a pre-warmed vm on its function's idle list or, if it failed to launch, free its memory
+    fn prewarmed(&mut self, mut vm: Vm, launched: bool) {
+        if let Some(prewarming) = self.prewarming.get_mut(&vm.function_name()) {
+            *prewarming = prewarming.saturating_sub(1);
+        }
+        self.record_boot(&mut vm);
+        if launched {
+            self.keep_idle(vm);
+        } else {
+            self.free(vm);
+        }
+    }
+
+    fn delete(&mut self, mut vm: Vm) {
+        self.record_boot(&mut vm);
+        self.finish(&vm);
+        self.free(vm);
+    }
+
+    // Shut down the vm and give back its memory and vcpus, to its tenant as well
+    fn free(&mut self, vm: Vm) {
         self.free_mem += vm.memory();
+        self.used_vcpus = self.used_vcpus.saturating_sub(vm.vcpus());
+        if let Some(usage) = vm.function_config().tenant.as_ref().and_then(|t| self.tenants.get_mut(t)) {
+            usage.memory = usage.memory.saturating_sub(vm.memory());
+            usage.vms = usage.vms.saturating_sub(1);
+        }
         drop(vm); // being explicit
     }
 
@@ -163,45 +415,209 @@ impl ResourceManager {
     // allocate() first checks if there's enough free resources by looking at `free_mem`. If there
     // is, it proactively "reserve" requisite memory by decrementing `free_mem`.
     //
-    // Allocation fail under 1 condition:
-    // when there's not enough resources on the machine (Err(Error::LowMemory))
+    // Allocation fail under 3 conditions:
+    // when the function's tenant is at its memory or vm cap (Err(Error::TenantQuota)),
+    // when there's not enough memory on the machine (Err(Error::LowMemory)), or
+    // when there's not enough vcpus under the vcpu limit (Err(Error::LowCpu))
     fn allocate(
         &mut self,
         function_name: &str,
     ) -> Result<Vm, Error> {
         let function_config = self.get_function_config(function_name)?.clone();
-        if self.free_mem >= function_config.memory {
-            self.total_num_vms += 1;
-            let id = self.total_num_vms;
-            self.free_mem -= function_config.memory;
+        let (tenant_mem, tenant_vms) = self.tenant_room(function_name);
+        if tenant_mem < function_config.memory || tenant_vms == 0 {
+            return Err(Error::TenantQuota(function_config.tenant.clone().unwrap_or_default()));
+        }
+        if self.available_mem(function_name) < function_config.memory {
+            return Err(Error::LowMemory(self.available_mem(function_name)));
+        }
+        if self.available_vcpus(function_name) < function_config.vcpus as usize {
+            return Err(Error::LowCpu(self.available_vcpus(function_name)));
+        }
+        self.total_num_vms += 1;
+        let id = self.total_num_vms;
+        self.free_mem -= function_config.memory;
+        self.used_vcpus += function_config.vcpus as usize;
+        if let Some(tenant) = function_config.tenant.as_ref() {
+            let usage = self.tenants.entry(tenant.clone()).or_default();
+            usage.memory += function_config.memory;
+            usage.vms += 1;
+        }
 
-            debug!("Allocating new VM. ID: {:?}, App: {:?}", id, function_name);
-            Ok(Vm::new(id, self.config.firerunner_path.clone(), function_name.to_string(), function_config, self.config.allow_network))
-        } else {
-            Err(Error::LowMemory(self.free_mem))
+        debug!("Allocating new VM. ID: {:?}, App: {:?}", id, function_name);
+        let mut vm = Vm::new(id, self.config.firerunner_path.clone(), function_name.to_string(), function_config, self.config.allow_network);
+        if let Some(cgroups) = self.config.cgroups.as_ref() {
+            vm.set_cgroup(cgroups.clone());
+        }
+        Ok(vm)
+    }
+
+    // Stop counting the vm among its function's vms serving requests
+    fn finish(&mut self, vm: &Vm) {
+        if let Some(busy) = self.busy.get_mut(&vm.function_name()) {
+            *busy = busy.saturating_sub(1);
         }
     }
 
-    // Evict one or more vms to free `mem` MB of memory.
-    // The function only return false when the `mem` MB is larger than the total available memory,
-    // which is expected to never happen in a production system.
-    fn evict(&mut self, mem: usize) -> bool {
-        if self.total_mem < mem {
+    // Number of vms of the function, serving requests, idle or being pre-warmed
+    fn num_vms(&self, function_name: &str) -> usize {
+        self.busy.get(function_name).copied().unwrap_or(0)
+            + self.idle.get(function_name).map_or(0, VmList::len)
+            + self.prewarming.get(function_name).copied().unwrap_or(0)
+    }
+
+    // Memory set aside by the `reserved_concurrency` of functions other than
+    // `function_name`, and by the `min_memory` of tenants other than its own,
+    // and not taken by their vms yet
+    fn reserved_mem(&self, function_name: &str) -> usize {
+        let tenant = self.tenant_of(function_name);
+        let functions: usize = self.config.functions.iter()
+            .filter(|(name, _)| name.as_str() != function_name)
+            .map(|(name, config)| config.reserved_concurrency.saturating_sub(self.num_vms(name)) * config.memory)
+            .sum();
+        let tenants: usize = self.config.tenants.iter()
+            .filter(|(name, _)| Some(name.as_str()) != tenant)
+            .map(|(name, config)| config.reserved(&self.tenant_usage(name)))
+            .sum();
+        functions + tenants
+    }
+
+    // Tenant the function belongs to, if any
+    fn tenant_of(&self, function_name: &str) -> Option<&str> {
+        self.config.functions.get(function_name)?.tenant.as_deref()
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> TenantUsage {
+        self.tenants.get(tenant).copied().unwrap_or_default()
+    }
+
+    // Memory and number of vms the function's tenant may still allocate,
+    // unlimited for functions without a tenant
+    fn tenant_room(&self, function_name: &str) -> (usize, usize) {
+        let tenant = self.tenant_of(function_name)
+            .and_then(|name| Some((name, self.config.tenants.get(name)?)));
+        match tenant {
+            Some((name, config)) => config.room(&self.tenant_usage(name)),
+            None => (usize::MAX, usize::MAX),
+        }
+    }
+
+    // Free memory `function_name` may allocate vms in
+    fn available_mem(&self, function_name: &str) -> usize {
+        self.free_mem.saturating_sub(self.reserved_mem(function_name))
+    }
+
+    // Like `reserved_mem` but for vcpus
+    fn reserved_vcpus(&self, function_name: &str) -> usize {
+        self.config.functions.iter()
+            .filter(|(name, _)| name.as_str() != function_name)
+            .map(|(name, config)| config.reserved_concurrency.saturating_sub(self.num_vms(name)) * config.vcpus as usize)
+            .sum()
+    }
+
+    // Number of vcpus under the vcpu limit `function_name` may allocate vms with
+    fn available_vcpus(&self, function_name: &str) -> usize {
+        match self.vcpu_limit {
+            Some(limit) => limit.saturating_sub(self.used_vcpus + self.reserved_vcpus(function_name)),
+            None => usize::MAX,
+        }
+    }
+
+    // Number of idle vms of `function_name` that may be evicted to make room for a vm of
+    // `for_function`. Other functions cannot evict a function's vms below its reservation,
+    // and functions of other tenants cannot evict a tenant's vms below its `min_memory`.
+    fn evictable(&self, function_name: &str, for_function: &str) -> usize {
+        let idle = self.idle.get(function_name).map_or(0, VmList::len);
+        if function_name == for_function {
+            return idle;
+        }
+        let reserved = self.config.functions.get(function_name).map_or(0, |f| f.reserved_concurrency);
+        let evictable = idle.min(self.num_vms(function_name).saturating_sub(reserved));
+        let tenant = self.tenant_of(function_name);
+        if tenant == self.tenant_of(for_function) {
+            return evictable;
+        }
+        match tenant.and_then(|name| Some((name, self.config.tenants.get(name)?))) {
+            Some((name, config)) => {
+                let memory = self.config.functions.get(function_name).map_or(1, |f| f.memory.max(1));
+                let spare = self.tenant_usage(name).memory.saturating_sub(config.min_memory);
+                evictable.min(spare / memory)
+            }
+            None => evictable,
+        }
+    }
+
+    // Feed the launch time of a newly launched vm to the eviction policy
+    fn record_boot(&mut self, vm: &mut Vm) {
+        if let Some(duration) = vm.take_launch_duration() {
+            self.usage.entry(vm.function_name()).or_default().record_boot(duration);
+        }
+    }
+
+    // Evict idle vms, chosen by the eviction policy, until `function_name` has `mem` MB of
+    // memory and `vcpus` vcpus available, and its tenant room for a vm of `mem` MB. Only the
+    // tenant's own idle vms make room under its caps. The function returns false, without
+    // evicting any vm, if idle vms do not hold enough memory or vcpus, and returns false as well
+    // if it runs out of vms it can evict halfway, e.g., because their idle lists are locked.
+    fn evict(&mut self, function_name: &str, mem: usize, vcpus: usize) -> bool {
+        let needed_mem = mem.saturating_sub(self.available_mem(function_name));
+        let needed_vcpus = vcpus.saturating_sub(self.available_vcpus(function_name));
+        let (tenant_mem, tenant_vms) = self.tenant_room(function_name);
+        let (needed_tenant_mem, needed_tenant_vms) = (mem.saturating_sub(tenant_mem), 1usize.saturating_sub(tenant_vms));
+        let tenant = self.tenant_of(function_name).map(String::from);
+        let (mut idle_mem, mut idle_vcpus, mut idle_tenant_mem, mut idle_tenant_vms) = (0, 0, 0, 0);
+        for name in self.idle.keys() {
+            if let Some(config) = self.config.functions.get(name) {
+                let evictable = self.evictable(name, function_name);
+                idle_mem += evictable * config.memory;
+                idle_vcpus += evictable * config.vcpus as usize;
+                if tenant.is_some() && config.tenant == tenant {
+                    idle_tenant_mem += evictable * config.memory;
+                    idle_tenant_vms += evictable;
+                }
+            }
+        }
+        if idle_mem < needed_mem || idle_vcpus < needed_vcpus
+            || idle_tenant_mem < needed_tenant_mem || idle_tenant_vms < needed_tenant_vms {
             return false;
         }
 
-        let mut freed: usize = 0;
-        while freed < mem {
-            for key in self.idle.keys() {
-                let vmlist = self.idle.get(key).unwrap();
+        let (mut freed_mem, mut freed_vcpus) = (0, 0);
+        let (mut freed_tenant_mem, mut freed_tenant_vms) = (0, 0);
+        // functions whose idle lists could not be popped
+        let mut exhausted = HashSet::new();
+        while freed_mem < needed_mem || freed_vcpus < needed_vcpus
+            || freed_tenant_mem < needed_tenant_mem || freed_tenant_vms < needed_tenant_vms {
+            let own_tenant_only = freed_tenant_mem < needed_tenant_mem || freed_tenant_vms < needed_tenant_vms;
+            let function = {
+                let candidates: Vec<Candidate> = self.idle.iter()
+                    .filter(|(name, _)| self.evictable(name, function_name) > 0 && !exhausted.contains(*name))
+                    .filter(|(name, _)| !own_tenant_only || self.tenant_of(name) == tenant.as_deref())
+                    .filter_map(|(name, _)| Some(Candidate {
+                        function: name,
+                        memory: self.config.functions.get(name)?.memory,
+                        usage: self.usage.get(name).cloned().unwrap_or_default(),
+                    }))
+                    .collect();
+                match self.eviction.select(&candidates) {
+                    Some(i) => candidates[i].function.to_string(),
+                    None => return false,
+                }
+            };
 
-                // instead of evicting from the first non-empty list in the map,
-                // collect some function popularity data and evict based on that.
-                // This is where some policies can be implemented.
-                if let Some(vm) = vmlist.try_pop() {
-                    freed += vm.memory();
-                    self.free_mem += vm.memory();
-                    drop(vm); // being explicit
+            match self.idle.get(&function).and_then(VmList::try_pop_oldest) {
+                Some(vm) => {
+                    debug!("Evicting VM {} of function {:?}", vm.id(), function);
+                    freed_mem += vm.memory();
+                    freed_vcpus += vm.vcpus();
+                    if tenant.is_some() && vm.function_config().tenant == tenant {
+                        freed_tenant_mem += vm.memory();
+                        freed_tenant_vms += 1;
+                    }
+                    self.free(vm);
+                }
+                None => {
+                    exhausted.insert(function);
                 }
             }
         }
@@ -214,12 +630,16 @@ impl ResourceManager {
     }
 }
 
+fn unix_now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
 impl Drop for ResourceManager {
     fn drop(&mut self) {
         for key in self.idle.keys() {
             let vmlist = self.idle.get(key).unwrap();
             vmlist.list.lock().map(|mut l| {
-                for vm in l.iter_mut() {
+                for (_, vm) in l.iter_mut() {
                     drop(vm); // Just being explicit here, not strictly necessary
                 }
             }).expect("poisoned lock");
@@ -239,7 +659,7 @@ impl VmList {
     /// This function blocks if it cannot grab the lock on self.list.
     pub fn pop(&self) -> Option<Vm> {
         match self.list.lock().expect("poisoned lock on idle list").pop() {
-            Some(v) => {
+            Some((_, v)) => {
                 self.num_vms.fetch_sub(1, Ordering::Relaxed);
                 return Some(v);
             }
@@ -252,7 +672,7 @@ impl VmList {
     pub fn try_pop(&self) -> Option<Vm> {
         match self.list.try_lock() {
             Ok(mut locked_list) => match locked_list.pop() {
-                Some(vm) => {
+                Some((_, vm)) => {
                     self.num_vms.fetch_sub(1, Ordering::Relaxed);
                     return Some(vm);
                 }
@@ -262,11 +682,326 @@ impl VmList {
         }
     }
 
+    /// Like `try_pop` but return the vm that has been idle the longest
+    pub fn try_pop_oldest(&self) -> Option<Vm> {
+        match self.list.try_lock() {
+            Ok(mut locked_list) if !locked_list.is_empty() => {
+                self.num_vms.fetch_sub(1, Ordering::Relaxed);
+                Some(locked_list.remove(0).1)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the number of vms in the list
+    pub fn len(&self) -> usize {
+        self.num_vms.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return the vms that have been idle for at least `ttl`,
+    /// but leave at least `keep` vms in the list
+    pub fn pop_expired(&self, ttl: Duration, keep: usize) -> Vec<Vm> {
+        let mut list = self.list.lock().expect("poisoned lock on idle list");
+        let expired = list.iter()
+            .take_while(|(idle_since, _)| idle_since.elapsed() >= ttl)
+            .count()
+            .min(list.len().saturating_sub(keep));
+        self.num_vms.fetch_sub(expired, Ordering::Relaxed);
+        list.drain(..expired).map(|(_, vm)| vm).collect()
+    }
+
     pub fn push(&self, val: Vm) {
         self.list
             .lock()
             .expect("poisoned lock on idle list")
-            .push(val);
+            .push((Instant::now(), val));
         self.num_vms.fetch_add(1, Ordering::Relaxed);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::Eviction;
+    use crate::prediction::PredictionConfig;
+    use crate::tenant::TenantConfig;
+
+    #[test]
+    fn test_evict() {
+        let mut config = ResourceManagerConfig { eviction: Eviction::Lru, ..Default::default() };
+        for (name, memory) in &[("small", 128), ("medium", 256), ("big", 512)] {
+            let function = FunctionConfig { memory: *memory, concurrency_limit: 10, ..Default::default() };
+            config.functions.insert(name.to_string(), function);
+        }
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 0;
+        for (id, name) in ["small", "small", "big"].iter().enumerate() {
+            let function_config = manager.config.functions[*name].clone();
+            manager.release(Vm::new(id, String::new(), name.to_string(), function_config, false));
+        }
+        // "big" was used least recently
+        manager.usage.entry("big".to_string()).or_default().record_use();
+        manager.usage.entry("small".to_string()).or_default().record_use();
+
+        assert!(manager.evict("medium", 256, 1));
+        assert_eq!(manager.free_mem, 512);
+        assert!(manager.idle["big"].is_empty());
+        assert_eq!(manager.idle["small"].len(), 2);
+
+        // idle VMs hold too little memory, evict none of them
+        assert!(!manager.evict("medium", 2048, 1));
+        assert_eq!(manager.free_mem, 512);
+        assert_eq!(manager.idle["small"].len(), 2);
+
+        // enough memory is already free
+        assert!(manager.evict("medium", 512, 1));
+        assert_eq!(manager.idle["small"].len(), 2);
+
+        // a new VM that does not fit evicts idle VMs, least recently used first
+        let big = manager.acquire_vm("big").unwrap();
+        assert_eq!(manager.free_mem, 0);
+        manager.release(big);
+        assert!(manager.acquire_vm("medium").is_ok());
+        assert!(manager.idle["small"].is_empty());
+        assert_eq!(manager.idle["big"].len(), 1);
+        assert!(manager.acquire_vm("medium").is_ok());
+        assert!(manager.idle["big"].is_empty());
+        assert_eq!(manager.free_mem, 256);
+        assert!(matches!(manager.acquire_vm("big"), Err(Error::InsufficientEvict)));
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let mut config = ResourceManagerConfig::default();
+        let noisy = FunctionConfig { memory: 128, concurrency_limit: 5, ..Default::default() };
+        let quiet = FunctionConfig { memory: 256, concurrency_limit: 2, reserved_concurrency: 2, ..Default::default() };
+        config.functions.insert("noisy".to_string(), noisy);
+        config.functions.insert("quiet".to_string(), quiet);
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+
+        // "noisy" cannot take the 512 MB reserved for "quiet"
+        let mut vms: Vec<Vm> = (0..4).map(|_| manager.acquire_vm("noisy").unwrap()).collect();
+        assert!(matches!(manager.acquire_vm("noisy"), Err(Error::InsufficientEvict)));
+        vms.push(manager.acquire_vm("quiet").unwrap());
+        vms.push(manager.acquire_vm("quiet").unwrap());
+        assert_eq!(manager.free_mem, 0);
+        assert!(matches!(manager.acquire_vm("quiet"), Err(Error::ConcurrencyLimit(2))));
+
+        // idle VMs of "quiet" stay reserved, "noisy" can only evict its own
+        for vm in vms.drain(..) {
+            manager.release(vm);
+        }
+        assert!(!manager.evict("noisy", 640, 1));
+        assert_eq!(manager.idle["noisy"].len(), 4);
+        assert!(manager.evict("quiet", 256, 1));
+        assert_eq!(manager.idle["noisy"].len(), 2);
+        assert_eq!(manager.idle["quiet"].len(), 2);
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let mut config = ResourceManagerConfig::default();
+        let warm = FunctionConfig { memory: 128, min_idle: 2, max_idle: Some(2), concurrency_limit: 10, ..Default::default() };
+        config.functions.insert("warm".to_string(), warm);
+        config.functions.insert("cold".to_string(), FunctionConfig { memory: 128, ..Default::default() });
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+
+        let first = manager.prewarm_vm().unwrap();
+        let second = manager.prewarm_vm().unwrap();
+        assert_eq!(first.function_name(), "warm");
+        // both VMs are being launched
+        assert!(manager.prewarm_vm().is_none());
+        assert_eq!(manager.free_mem, 768);
+
+        manager.prewarmed(first, true);
+        manager.prewarmed(second, false);
+        assert_eq!(manager.idle["warm"].len(), 1);
+        assert_eq!(manager.free_mem, 896);
+        let third = manager.prewarm_vm().unwrap();
+        manager.prewarmed(third, true);
+        assert!(manager.prewarm_vm().is_none());
+
+        // VMs released beyond max_idle are shut down
+        let vms: Vec<Vm> = (0..3).map(|_| manager.acquire_vm("warm").unwrap()).collect();
+        assert_eq!(manager.free_mem, 640);
+        for vm in vms {
+            manager.release(vm);
+        }
+        assert_eq!(manager.idle["warm"].len(), 2);
+        assert_eq!(manager.free_mem, 768);
+    }
+
+    #[test]
+    fn test_reap_idle() {
+        let mut config = ResourceManagerConfig::default();
+        let function = FunctionConfig { min_idle: 1, keep_alive: Some(100), concurrency_limit: 10, ..Default::default() };
+        config.functions.insert("burst".to_string(), function);
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+
+        let vms: Vec<Vm> = (0..3).map(|_| manager.acquire_vm("burst").unwrap()).collect();
+        for vm in vms {
+            manager.release(vm);
+        }
+        manager.reap_idle();
+        assert_eq!(manager.idle["burst"].len(), 3);
+
+        std::thread::sleep(Duration::from_millis(150));
+        manager.reap_idle();
+        assert_eq!(manager.idle["burst"].len(), 1);
+        assert_eq!(manager.free_mem, 1024 - 128);
+    }
+
+    #[test]
+    fn test_predict() {
+        let prediction = PredictionConfig::default();
+        let mut config = ResourceManagerConfig { prediction: Some(prediction.clone()), ..Default::default() };
+        let cron = FunctionConfig { keep_alive: Some(100), concurrency_limit: 10, ..Default::default() };
+        config.functions.insert("cron".to_string(), cron);
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+
+        // a request every 30 minutes, the last one 29 minutes ago
+        let minute = Duration::from_secs(60);
+        let last = unix_now() - minute * 29;
+        let mut history = ArrivalHistory::new(&prediction);
+        for i in (0..20).rev() {
+            history.record(last - minute * 30 * i, &prediction);
+        }
+        manager.arrivals.insert("cron".to_string(), history);
+        manager.predict();
+        let vm = manager.prewarm_vm().unwrap();
+        manager.prewarmed(vm, true);
+        assert!(manager.prewarm_vm().is_none());
+        assert_eq!(manager.idle["cron"].len(), 1);
+
+        // the request came, the next one is not expected for a while, but the VM is kept
+        // for the function's keep_alive
+        let vm = manager.acquire_vm("cron").unwrap();
+        manager.release(vm);
+        manager.predict();
+        assert_eq!(manager.idle["cron"].len(), 1);
+        std::thread::sleep(Duration::from_millis(150));
+        manager.predict();
+        assert!(manager.idle["cron"].is_empty());
+        assert_eq!(manager.free_mem, 1024);
+        assert!(manager.prewarm_vm().is_none());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut config = ResourceManagerConfig::default();
+        for name in &["kept", "changed", "removed"] {
+            let function = FunctionConfig { concurrency_limit: 10, ..Default::default() };
+            config.functions.insert(name.to_string(), function);
+        }
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+        for name in &["kept", "changed", "removed"] {
+            let vm = manager.acquire_vm(name).unwrap();
+            manager.release(vm);
+        }
+        let busy = manager.acquire_vm("changed").unwrap();
+        assert_eq!(manager.free_mem, 1024 - 4 * 128);
+
+        let mut config = ResourceManagerConfig::default();
+        config.functions.insert("kept".to_string(), FunctionConfig { concurrency_limit: 5, ..Default::default() });
+        config.functions.insert("changed".to_string(), FunctionConfig { memory: 256, ..Default::default() });
+        config.functions.insert("added".to_string(), FunctionConfig::default());
+        manager.reload(config);
+        assert_eq!(manager.idle["kept"].len(), 1);
+        assert!(manager.idle["changed"].is_empty());
+        assert!(manager.idle["added"].is_empty());
+        assert!(!manager.idle.contains_key("removed"));
+        assert_eq!(manager.free_mem, 1024 - 2 * 128);
+
+        // the old VM is deleted once its request finishes
+        manager.release(busy);
+        assert!(manager.idle["changed"].is_empty());
+        assert_eq!(manager.free_mem, 1024 - 128);
+        assert!(matches!(manager.acquire_vm("removed"), Err(Error::FunctionNotExist)));
+        assert_eq!(manager.acquire_vm("changed").unwrap().memory(), 256);
+    }
+
+    #[test]
+    fn test_vcpus() {
+        let mut config = ResourceManagerConfig::default();
+        config.functions.insert("wide".to_string(), FunctionConfig { vcpus: 2, concurrency_limit: 10, ..Default::default() });
+        config.functions.insert("narrow".to_string(), FunctionConfig { vcpus: 1, concurrency_limit: 10, ..Default::default() });
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+        // 2 CPUs overcommitted twice
+        manager.set_cpu_overcommit(2, 2.0);
+        assert_eq!(manager.vcpu_limit(), Some(4));
+
+        let first = manager.acquire_vm("wide").unwrap();
+        let second = manager.acquire_vm("wide").unwrap();
+        assert!(matches!(manager.allocate("narrow"), Err(Error::LowCpu(0))));
+        assert!(matches!(manager.acquire_vm("narrow"), Err(Error::InsufficientEvict)));
+
+        // an idle VM is evicted for its vCPUs even though memory is plentiful
+        manager.release(first);
+        let narrow = manager.acquire_vm("narrow").unwrap();
+        assert!(manager.idle["wide"].is_empty());
+        assert_eq!(manager.used_vcpus, 3);
+        assert_eq!(manager.free_mem, 1024 - 2 * 128);
+
+        manager.delete(second);
+        manager.delete(narrow);
+        assert_eq!(manager.used_vcpus, 0);
+    }
+
+    #[test]
+    fn test_tenants() {
+        let mut config = ResourceManagerConfig::default();
+        config.tenants.insert("course".to_string(), TenantConfig { max_memory: Some(256), ..Default::default() });
+        config.tenants.insert("team".to_string(), TenantConfig { min_memory: 256, ..Default::default() });
+        for (name, tenant) in &[("grader", Some("course")), ("grader@v2", Some("course")), ("lab", Some("team")), ("shared", None)] {
+            let function = FunctionConfig { concurrency_limit: 10, tenant: tenant.map(String::from), ..Default::default() };
+            config.functions.insert(name.to_string(), function);
+        }
+        let (mut manager, _) = ResourceManager::new(config);
+        manager.total_mem = 1024;
+        manager.free_mem = 1024;
+
+        // a tenant at its cap only evicts its own idle VMs
+        let grader = manager.acquire_vm("grader").unwrap();
+        let _grader_v2 = manager.acquire_vm("grader@v2").unwrap();
+        assert!(matches!(manager.acquire_vm("grader"), Err(Error::TenantQuota(t)) if t == "course"));
+        manager.release(grader);
+        let _grader_v2 = manager.acquire_vm("grader@v2").unwrap();
+        assert!(manager.idle["grader"].is_empty());
+        assert_eq!(manager.tenants["course"], TenantUsage { memory: 256, vms: 2 });
+
+        // other functions cannot allocate the memory set aside for "team"
+        let shared: Vec<Vm> = (0..4).map(|_| manager.acquire_vm("shared").unwrap()).collect();
+        assert!(matches!(manager.acquire_vm("shared"), Err(Error::InsufficientEvict)));
+        let labs: Vec<Vm> = (0..2).map(|_| manager.acquire_vm("lab").unwrap()).collect();
+        assert_eq!(manager.free_mem, 0);
+
+        // nor evict its idle VMs below it
+        for lab in labs {
+            manager.release(lab);
+        }
+        assert!(matches!(manager.acquire_vm("shared"), Err(Error::InsufficientEvict)));
+        assert_eq!(manager.idle["lab"].len(), 2);
+
+        for vm in shared {
+            manager.delete(vm);
+        }
+        assert_eq!(manager.free_mem, 512);
+    }
+}
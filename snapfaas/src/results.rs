@@ -0,0 +1,190 @@
+//! Result store for asynchronous invocations
+//!
+//! A gateway registers an asynchronous request under a fresh random ID and responds to
+//! the client with that ID right away. The worker that serves the request stores the
+//! `Response` under the same ID in the `results` database of `labeled_fs::DBENV`.
+//! Clients then fetch the result by ID, optionally waiting for it to be stored.
+//! Results that clients never remove are swept once they are older than a TTL.
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use lmdb::{Cursor, Transaction, WriteFlags};
+use serde::{Deserialize, Serialize};
+
+use crate::labeled_fs::DBENV;
+use crate::request::Response;
+
+const RESULTS_DB: &str = "results";
+/// how long results are kept, by default, after they were last written
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref RESULTS: lmdb::Database =
+        DBENV.create_db(Some(RESULTS_DB), lmdb::DatabaseFlags::empty()).unwrap();
+    // notified every time a result is stored
+    static ref STORED: (Mutex<()>, Condvar) = (Mutex::new(()), Condvar::new());
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AsyncResult {
+    Pending,
+    Done(Response),
+}
+
+// a result with the time in seconds since the Unix epoch it was written
+#[derive(Debug, Serialize, Deserialize)]
+struct Record<R> {
+    result: R,
+    stored_at: u64,
+}
+
+/// Register a new asynchronous request and return its ID
+pub fn register() -> Result<String, lmdb::Error> {
+    let id = hex::encode(rand::random::<[u8; 16]>());
+    put(&id, &AsyncResult::Pending)?;
+    Ok(id)
+}
+
+/// Store the response of the asynchronous request `id` and wake up waiting clients
+pub fn complete(id: &str, response: Response) -> Result<(), lmdb::Error> {
+    put(id, &AsyncResult::Done(response))?;
+    let (lock, cvar) = &*STORED;
+    let _guard = lock.lock().unwrap();
+    cvar.notify_all();
+    Ok(())
+}
+
+/// Return the result of request `id`, or None if no such request was registered
+pub fn get(id: &str) -> Option<AsyncResult> {
+    let txn = DBENV.begin_ro_txn().unwrap();
+    let res = txn.get(*RESULTS, &id).ok().and_then(|v| serde_json::from_slice::<Record<AsyncResult>>(v).ok());
+    let _ = txn.commit();
+    res.map(|record| record.result)
+}
+
+/// Like `get` but if the request is still pending, block until its result is stored
+/// or `timeout` elapses
+pub fn wait(id: &str, timeout: Duration) -> Option<AsyncResult> {
+    let deadline = Instant::now() + timeout;
+    let (lock, cvar) = &*STORED;
+    // checking under the lock guarantees that no notification is missed
+    let mut guard = lock.lock().unwrap();
+    loop {
+        match get(id) {
+            Some(AsyncResult::Pending) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Some(AsyncResult::Pending);
+                }
+                guard = cvar.wait_timeout(guard, deadline - now).unwrap().0;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Remove the result of request `id`. Return false if there is no such request.
+pub fn remove(id: &str) -> bool {
+    let mut txn = DBENV.begin_rw_txn().unwrap();
+    let res = txn.del(*RESULTS, &id, None).is_ok();
+    txn.commit().unwrap();
+    res
+}
+
+/// Remove the results, pending or done, last written at least `ttl` ago, as well as records
+/// that cannot be decoded. Return the number of results removed.
+pub fn sweep(ttl: Duration) -> usize {
+    let now = unix_secs();
+    let txn = DBENV.begin_ro_txn().unwrap();
+    let expired: Vec<Vec<u8>> = {
+        let mut cursor = txn.open_ro_cursor(*RESULTS).unwrap();
+        cursor.iter_start()
+            .filter_map(Result::ok)
+            .filter(|(_, v)| serde_json::from_slice::<Record<AsyncResult>>(v)
+                .map_or(true, |record| now.saturating_sub(record.stored_at) >= ttl.as_secs()))
+            .map(|(k, _)| k.to_vec())
+            .collect()
+    };
+    let _ = txn.commit();
+    if expired.is_empty() {
+        return 0;
+    }
+
+    let mut txn = DBENV.begin_rw_txn().unwrap();
+    let removed = expired.iter().filter(|id| txn.del(*RESULTS, id, None).is_ok()).count();
+    txn.commit().unwrap();
+    removed
+}
+
+/// Sweep results older than `ttl` every minute, in the background
+pub fn start_sweeper(ttl: Duration) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let removed = sweep(ttl);
+        if removed > 0 {
+            debug!("Removed {} expired results", removed);
+        }
+    })
+}
+
+fn put(id: &str, result: &AsyncResult) -> Result<(), lmdb::Error> {
+    put_at(id, result, unix_secs())
+}
+
+fn put_at(id: &str, result: &AsyncResult, stored_at: u64) -> Result<(), lmdb::Error> {
+    let record = Record { result, stored_at };
+    let mut txn = DBENV.begin_rw_txn().unwrap();
+    txn.put(*RESULTS, &id, &serde_json::to_vec(&record).unwrap(), WriteFlags::empty())?;
+    txn.commit()
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{RequestError, RequestStatus};
+
+    #[test]
+    fn test_results_register_complete_wait() {
+        assert!(get("no-such-request").is_none());
+
+        let id = register().unwrap();
+        assert!(matches!(get(&id), Some(AsyncResult::Pending)));
+        assert!(matches!(wait(&id, Duration::from_millis(10)), Some(AsyncResult::Pending)));
+
+        let id_cloned = id.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            complete(&id_cloned, Response::failed(RequestStatus::ExecutionFailed, RequestError::AuthTokenNotExist)).unwrap();
+        });
+        match wait(&id, Duration::from_secs(10)) {
+            Some(AsyncResult::Done(rsp)) => assert_eq!(rsp.error, Some(RequestError::AuthTokenNotExist)),
+            res => panic!("unexpected result {:?}", res),
+        }
+        handle.join().unwrap();
+
+        assert!(remove(&id));
+        assert!(get(&id).is_none());
+        assert!(!remove(&id));
+    }
+
+    #[test]
+    fn test_results_sweep() {
+        let fresh = register().unwrap();
+        let stale = hex::encode(rand::random::<[u8; 16]>());
+        put_at(&stale, &AsyncResult::Done(Response::new(RequestStatus::SentToVM)), 0).unwrap();
+        assert!(matches!(get(&stale), Some(AsyncResult::Done(_))));
+
+        // only results written before the TTL expire
+        assert!(sweep(Duration::from_secs(365 * 24 * 60 * 60)) >= 1);
+        assert!(get(&stale).is_none());
+        assert!(matches!(get(&fresh), Some(AsyncResult::Pending)));
+        assert!(remove(&fresh));
+    }
+}
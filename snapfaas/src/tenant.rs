@@ -0,0 +1,91 @@
+//! Tenants sharing the memory of a controller
+//!
+//! Groups sharing a host declare themselves under `tenants` in the controller YAML and assign
+//! their functions to a tenant with `tenant`:
+//!
+//! ```yaml
+//! tenants:
+//!   cos316:
+//!     max_memory: 8192   # MB its VMs may add up to
+//!     max_vms: 64        # number of VMs it may have
+//!   sns:
+//!     min_memory: 2048   # MB set aside for its VMs
+//! functions:
+//!   grader:
+//!     tenant: cos316
+//!     ...
+//! ```
+//!
+//! The resource manager counts every VM of a tenant's functions, serving requests, idle or
+//! being pre-warmed, against the tenant. A tenant at its `max_memory` or `max_vms` evicts its
+//! own idle VMs to make room for a new one, never those of other tenants. Like
+//! `reserved_concurrency` for a function, a tenant's `min_memory` not taken by its VMs cannot
+//! be allocated by other tenants' functions, which cannot evict its idle VMs below it either.
+//! Functions without a tenant are only limited by the memory of the controller.
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TenantConfig {
+    /// memory in MB the tenant's VMs may add up to, unlimited if None
+    pub max_memory: Option<usize>,
+    /// number of VMs the tenant may have, unlimited if None
+    pub max_vms: Option<usize>,
+    /// memory in MB set aside for the tenant's VMs
+    pub min_memory: usize,
+}
+
+impl TenantConfig {
+    /// Check that the tenant's guarantee fits under its caps
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_memory.map_or(false, |max| self.min_memory > max) {
+            return Err("min_memory is larger than max_memory".to_string());
+        }
+        if self.max_vms == Some(0) {
+            return Err("max_vms cannot be 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Return the memory and the number of VMs the tenant may still allocate, given `usage`
+    pub fn room(&self, usage: &TenantUsage) -> (usize, usize) {
+        (
+            self.max_memory.map_or(usize::MAX, |max| max.saturating_sub(usage.memory)),
+            self.max_vms.map_or(usize::MAX, |max| max.saturating_sub(usage.vms)),
+        )
+    }
+
+    /// Return the memory set aside for the tenant and not taken by its VMs
+    pub fn reserved(&self, usage: &TenantUsage) -> usize {
+        self.min_memory.saturating_sub(usage.memory)
+    }
+}
+
+/// Resources held by a tenant's VMs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    /// memory in MB
+    pub memory: usize,
+    pub vms: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant() {
+        let tenant: TenantConfig = serde_yaml::from_str("{max_memory: 1024, max_vms: 4, min_memory: 256}").unwrap();
+        assert!(tenant.validate().is_ok());
+        assert_eq!(tenant.room(&TenantUsage::default()), (1024, 4));
+        assert_eq!(tenant.reserved(&TenantUsage::default()), 256);
+        let usage = TenantUsage { memory: 512, vms: 4 };
+        assert_eq!(tenant.room(&usage), (512, 0));
+        assert_eq!(tenant.reserved(&usage), 0);
+
+        let unlimited = TenantConfig::default();
+        assert_eq!(unlimited.room(&usage), (usize::MAX, usize::MAX));
+        let inconsistent: TenantConfig = serde_yaml::from_str("{max_memory: 128, min_memory: 256}").unwrap();
+        assert!(inconsistent.validate().is_err());
+    }
+}
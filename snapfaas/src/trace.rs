@@ -0,0 +1,188 @@
+//! Trace context of requests and export of request timestamps as OTLP traces
+//!
+//! Every request served by a worker is a span. A request from a client starts a new trace
+//! unless it carries a `trace` of its own; a request a function makes through the invoke
+//! syscall, or a workflow makes for one of its steps, is a child span of the invoking request.
+//! Workers record each request's trace context with its timestamps in `out/thread-*.stat` and
+//! export the same records as OTLP JSON to `out/thread-*.traces.json`, one
+//! `ExportTraceServiceRequest` per line, which the OpenTelemetry collector's file receiver
+//! and most tracing backends read.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::metrics::RequestTimestamps;
+
+const SERVICE_NAME: &str = "snapfaas";
+// OTLP span kind and status codes
+const SPAN_KIND_SERVER: u32 = 2;
+const STATUS_CODE_OK: u32 = 1;
+const STATUS_CODE_ERROR: u32 = 2;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 16 random bytes in hex, shared by every request of an invocation tree
+    pub trace_id: String,
+    /// 8 random bytes in hex, identifies the request. A client may leave it empty.
+    #[serde(default)]
+    pub span_id: String,
+    /// span of the invoking request, None for the root of the tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Return the context of a request that starts a new trace
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: hex::encode(rand::random::<[u8; 16]>()),
+            span_id: new_span_id(),
+            parent_span_id: None,
+        }
+    }
+
+    /// Return the context of a request made on behalf of the request with this context
+    pub fn child(&self) -> Self {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            parent_span_id: Some(self.span_id.clone()),
+        }
+    }
+
+    /// Return `trace`, with a span ID if it has none, or a new root context
+    pub fn or_root(trace: Option<TraceContext>) -> Self {
+        match trace {
+            Some(mut trace) => {
+                if trace.span_id.is_empty() {
+                    trace.span_id = new_span_id();
+                }
+                trace
+            }
+            None => TraceContext::root(),
+        }
+    }
+}
+
+fn new_span_id() -> String {
+    hex::encode(rand::random::<[u8; 8]>())
+}
+
+/// Return the value to add to `time::precise_time_ns` timestamps of this process
+/// to turn them into Unix time
+pub fn unix_offset() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    now.saturating_sub(time::precise_time_ns())
+}
+
+/// Convert the timestamps of requests that carry a trace context to an OTLP JSON
+/// `ExportTraceServiceRequest`. `unix_offset` is added to every timestamp.
+pub fn to_otlp(records: &[RequestTimestamps], unix_offset: u64) -> Value {
+    let spans: Vec<Value> = records.iter().filter_map(|t| {
+        let trace = t.request.trace.as_ref()?;
+        let start = if t.at_gateway != 0 { t.at_gateway } else { t.at_vmm };
+        // a request that failed ends at the last stage it reached
+        let end = [t.completed, t.launched, t.allocated, t.arrived].iter().copied().find(|&at| at != 0)?;
+        let status = if t.completed != 0 { STATUS_CODE_OK } else { STATUS_CODE_ERROR };
+        let events: Vec<Value> = [("arrived", t.arrived), ("allocated", t.allocated), ("launched", t.launched)]
+            .iter()
+            .filter(|(_, at)| *at != 0)
+            .map(|(name, at)| json!({ "name": name, "timeUnixNano": (at + unix_offset).to_string() }))
+            .collect();
+        let mut span = json!({
+            "traceId": trace.trace_id,
+            "spanId": trace.span_id,
+            "name": t.request.function,
+            "kind": SPAN_KIND_SERVER,
+            "startTimeUnixNano": (start + unix_offset).to_string(),
+            "endTimeUnixNano": (end + unix_offset).to_string(),
+            "attributes": [
+                { "key": "faas.invoked_name", "value": { "stringValue": t.request.function } },
+                { "key": "faas.coldstart", "value": { "boolValue": t.cold_start } },
+                { "key": "snapfaas.queue_depth", "value": { "intValue": t.queue_depth.to_string() } },
+            ],
+            "events": events,
+            "status": { "code": status },
+        });
+        if let Some(parent) = trace.parent_span_id.as_ref() {
+            span["parentSpanId"] = json!(parent);
+        }
+        // the request was routed to this version of a function through an alias
+        if let (Some(alias), Some(attributes)) = (t.request.alias.as_ref(), span["attributes"].as_array_mut()) {
+            attributes.push(json!({ "key": "snapfaas.alias", "value": { "stringValue": alias } }));
+        }
+        // resources the VM used, if it is in a cgroup
+        if let (Some(usage), Some(attributes)) = (t.usage.as_ref(), span["attributes"].as_array_mut()) {
+            attributes.push(json!({ "key": "snapfaas.cpu_time_us", "value": { "intValue": usage.cpu_time.to_string() } }));
+            attributes.push(json!({ "key": "snapfaas.peak_memory_bytes", "value": { "intValue": usage.peak_memory.to_string() } }));
+        }
+        Some(span)
+    }).collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": SERVICE_NAME } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Request;
+
+    #[test]
+    fn test_trace_context() {
+        let root = TraceContext::root();
+        assert_eq!(root.trace_id.len(), 32);
+        assert_eq!(root.span_id.len(), 16);
+        let child = root.child();
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_eq!(child.parent_span_id, Some(root.span_id.clone()));
+        assert_ne!(child.span_id, root.span_id);
+
+        let from_client: TraceContext = serde_json::from_str(r#"{"trace_id": "0af7651916cd43dd8448eb211c80319c"}"#).unwrap();
+        let trace = TraceContext::or_root(Some(from_client));
+        assert_eq!(trace.trace_id, "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(trace.span_id.len(), 16);
+    }
+
+    #[test]
+    fn test_to_otlp() {
+        let root = TraceContext::root();
+        let parent = RequestTimestamps {
+            at_gateway: 100,
+            arrived: 110,
+            allocated: 120,
+            launched: 130,
+            completed: 200,
+            request: Request { function: "caller".to_string(), trace: Some(root.clone()), ..Default::default() },
+            ..Default::default()
+        };
+        let child = RequestTimestamps {
+            at_vmm: 150,
+            arrived: 160,
+            request: Request { function: "callee".to_string(), trace: Some(root.child()), ..Default::default() },
+            ..Default::default()
+        };
+        let untraced = RequestTimestamps { arrived: 1, ..Default::default() };
+
+        let otlp = to_otlp(&[parent, child, untraced], 1000);
+        let spans = otlp["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["name"], "caller");
+        assert_eq!(spans[0]["startTimeUnixNano"], "1100");
+        assert_eq!(spans[0]["endTimeUnixNano"], "1200");
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(spans[1]["parentSpanId"], json!(root.span_id));
+        assert_eq!(spans[1]["traceId"], json!(root.trace_id));
+        assert_eq!(spans[1]["status"]["code"], STATUS_CODE_ERROR);
+    }
+}
@@ -5,19 +5,21 @@ use std::net::Shutdown;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::process::Stdio;
 use std::string::String;
-use std::sync::mpsc::Sender;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, Sender};
 use std::io::{Seek, Write};
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use log::{debug, error};
 use tokio::process::{Child, Command};
 use serde_json::Value;
 
+use crate::cgroup::{Cgroup, CgroupConfig, Usage};
 use crate::configs::FunctionConfig;
-use crate::message::Message;
+use crate::queue::RequestQueue;
 use crate::{blobstore, syscalls};
-use crate::request::Request;
+use crate::request::{Request, RequestStatus, Response};
+use crate::trace::TraceContext;
 use crate::labeled_fs::{self, DBENV};
 
 const MACPREFIX: &str = "AA:BB:CC:DD";
@@ -56,6 +58,11 @@ fn proto_label_to_dc_label(label: syscalls::DcLabel) -> DCLabel {
     }
 }
 
+/// Starting label of a function's requests, with public secrecy and integrity has app-name
+fn initial_label(function_name: &str) -> DCLabel {
+    DCLabel::new(true, [[function_name.to_string()]])
+}
+
 fn dc_label_to_proto_label(label: &DCLabel) -> syscalls::DcLabel {
     syscalls::DcLabel {
         secrecy: match &label.secrecy {
@@ -98,6 +105,10 @@ pub enum Error {
     AppfsNotExist,
     LoadDirNotExist,
     IOError(std::io::Error),
+    /// the function did not respond before the deadline
+    Timeout,
+    /// the VM could not be placed in its cgroup
+    Cgroup(std::io::Error),
 }
 
 impl From<std::io::Error> for Error {
@@ -106,6 +117,14 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// A read from the VM that hit the socket's read timeout means the deadline passed
+fn vsock_read_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Error::Timeout,
+        _ => Error::VsockRead(e),
+    }
+}
+
 /// Specify the `O_DIRECT` flag when open a disk image which is a regular file
 pub struct OdirectOption {
     pub base: bool,
@@ -119,12 +138,13 @@ struct VmHandle {
     conn: UnixStream,
     //currently every VM instance opens a connection to the REST server
     rest_client: reqwest::blocking::Client,
-    #[allow(dead_code)]
-    // This field is never used, but we need to it make sure the Child isn't dropped and, thus,
-    // killed, before the VmHandle is dropped.
+    // Besides being killed by `Vm::kill`, the Child is killed when dropped, so it must
+    // live as long as the VmHandle.
     vm_process: Child,
     // None when VM is created from single-VM launcher
-    invoke_handle: Option<Sender<Message>>,
+    invoke_handle: Option<RequestQueue>,
+    // dropped after vm_process, whose cgroup can only be removed once it exited
+    cgroup: Option<Cgroup>,
 }
 
 #[derive(Debug)]
@@ -141,6 +161,14 @@ pub struct Vm {
     create_blobs: HashMap<u64, blobstore::NewBlob>,
     blobs: HashMap<u64, blobstore::Blob>,
     max_blob_id: u64,
+    // trace context of the request being processed, the parent of requests it invokes
+    trace: Option<TraceContext>,
+    // functions that invoked the request being processed, see `Request::ancestry`
+    ancestry: Vec<String>,
+    // time the VM took to launch, until taken by `take_launch_duration`
+    launch_duration: Option<Duration>,
+    // cgroups the VM is placed in when launched, see `cgroup`
+    cgroup_config: Option<CgroupConfig>,
 }
 
 impl Vm {
@@ -160,17 +188,25 @@ impl Vm {
             function_config,
             // We should also probably have a clearance to mitigate side channel attacks, but
             // meh for now...
-            /// Starting label with public secrecy and integrity has app-name
-            current_label: DCLabel::new(true, [[function_name.clone()]]),
+            current_label: initial_label(&function_name),
             privilege: Component::formula([[function_name]]),
             handle: None,
             blobstore: Default::default(),
             create_blobs: Default::default(),
             blobs: Default::default(),
             max_blob_id: 0,
+            trace: None,
+            ancestry: Vec::new(),
+            launch_duration: None,
+            cgroup_config: None,
         }
     }
 
+    /// Place the VM, once launched, in a cgroup limiting it to the resources of its function
+    pub fn set_cgroup(&mut self, config: CgroupConfig) {
+        self.cgroup_config = Some(config);
+    }
+
     /// Return true if the Vm instance is already launched, otherwise false.
     pub fn is_launched(&self) -> bool {
         self.handle.is_some()
@@ -180,12 +216,13 @@ impl Vm {
     /// When this function returns, the VM has finished booting and is ready to accept requests.
     pub fn launch(
         &mut self,
-        invoke_handle: Option<Sender<Message>>,
+        invoke_handle: Option<RequestQueue>,
         vm_listener: UnixListener,
         cid: u32,
         force_exit: bool,
         odirect: Option<OdirectOption>,
     ) -> Result<(), Error> {
+        let launch_start = Instant::now();
         let function_config = &self.function_config;
         let mem_str = function_config.memory.to_string();
         let vcpu_str = function_config.vcpus.to_string();
@@ -256,7 +293,7 @@ impl Vm {
         }
 
         let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
-        let (conn, vm_process) = runtime.block_on(async {
+        let (conn, vm_process, cgroup) = runtime.block_on(async {
             debug!("args: {:?}", args);
             let mut vm_process = Command::new(&self.firerunner).args(args).kill_on_drop(true)
                 .stdin(Stdio::null())
@@ -276,6 +313,14 @@ impl Vm {
                 std::process::exit(status);
             }
 
+            let cgroup = match (self.cgroup_config.as_ref(), vm_process.id()) {
+                (Some(config), Some(pid)) => Some(
+                    Cgroup::create(config, &self.function_name, self.id, &self.function_config, pid)
+                        .map_err(Error::Cgroup)?
+                ),
+                _ => None,
+            };
+
             let vm_listener = tokio::net::UnixListener::from_std(vm_listener).expect("convert from UnixListener std");
             let conn = tokio::select! {
                 res = vm_listener.accept() => {
@@ -287,7 +332,7 @@ impl Vm {
                 }
             };
             conn.set_nonblocking(false).map_err(|e| Error::VsockListen(e))?;
-            let x: Result<_, Error> = Ok((conn, vm_process));
+            let x: Result<_, Error> = Ok((conn, vm_process, cgroup));
             x
         })?;
 
@@ -298,13 +343,32 @@ impl Vm {
             rest_client,
             vm_process,
             invoke_handle,
+            cgroup,
         };
 
         self.handle = Some(handle);
+        self.launch_duration = Some(launch_start.elapsed());
 
         Ok(())
     }
 
+    /// Return how long the VM took to launch, only the first time it is called after the launch
+    pub fn take_launch_duration(&mut self) -> Option<Duration> {
+        self.launch_duration.take()
+    }
+
+    /// Return the CPU time in us the VM used so far, None if it is not in a cgroup
+    pub fn cpu_time(&self) -> Option<u64> {
+        let cgroup = self.handle.as_ref()?.cgroup.as_ref()?;
+        cgroup.cpu_time().map_err(|e| error!("Failed to read CPU time of VM {}: {:?}", self.id, e)).ok()
+    }
+
+    /// Return the resources the VM used since its CPU time was `cpu_time`
+    pub fn usage_since(&self, cpu_time: Option<u64>) -> Option<Usage> {
+        let cgroup = self.handle.as_ref()?.cgroup.as_ref()?;
+        cgroup.usage_since(cpu_time?).map_err(|e| error!("Failed to read usage of VM {}: {:?}", self.id, e)).ok()
+    }
+
     pub fn function_name(&self) -> String {
         self.function_name.clone()
     }
@@ -313,21 +377,78 @@ impl Vm {
         self.id
     }
 
+    /// Return the config the VM was created with
+    pub fn function_config(&self) -> &FunctionConfig {
+        &self.function_config
+    }
+
     /// Return function memory size in MB
     pub fn memory(&self) -> usize {
         self.function_config.memory
     }
 
+    /// Return the number of vCPUs of the VM
+    pub fn vcpus(&self) -> usize {
+        self.function_config.vcpus as usize
+    }
+
+    pub fn current_label(&self) -> &DCLabel {
+        &self.current_label
+    }
+
+    /// Raise the current label to at least `label`, e.g., the label of the callee
+    /// a synchronous invocation returned from
+    pub fn taint_with_label(&mut self, label: &DCLabel) {
+        self.current_label = self.current_label.clone().lub(label.clone());
+    }
+
+    /// Set the label the next request to process starts at: the function's initial label,
+    /// raised to at least `label`, the label of the function invoking it if any. A warm VM
+    /// thus neither carries over the label of the requests it served before nor passes it on.
+    pub fn set_label(&mut self, label: Option<&DCLabel>) {
+        self.current_label = initial_label(&self.function_name);
+        if let Some(label) = label {
+            self.taint_with_label(label);
+        }
+    }
+
+    /// Set the trace context of the next request to process. Requests it invokes
+    /// are traced as its children.
+    pub fn set_trace(&mut self, trace: Option<TraceContext>) {
+        self.trace = trace;
+    }
+
+    /// Set the functions that invoked the next request to process, from the outermost caller
+    /// to the direct caller. Requests it invokes are rejected if the chain gets too deep or
+    /// forms a cycle, see `queue::InvokeLimits`.
+    pub fn set_ancestry(&mut self, ancestry: Vec<String>) {
+        self.ancestry = ancestry;
+    }
+
     fn send_into_vm(&mut self, sys_req: Vec<u8>) -> Result<(), Error> {
         let mut conn = &self.handle.as_ref().unwrap().conn;
         conn.write_all(&(sys_req.len() as u32).to_be_bytes()).map_err(|e| Error::VsockWrite(e))?;
         conn.write_all(sys_req.as_ref()).map_err(|e| Error::VsockWrite(e))
     }
 
-    /// Send request to vm and wait for its response
-    pub fn process_req(&mut self, req: Value) -> Result<String, Error> {
+    /// Kill the firerunner process. The VM cannot process requests afterwards.
+    pub fn kill(&mut self) {
+        if let Some(handle) = self.handle.as_mut() {
+            if let Err(e) = handle.vm_process.start_kill() {
+                error!("Failed to kill VM {}: {:?}", self.id, e);
+            }
+        }
+    }
+
+    /// Send request to vm and wait for its response.
+    /// Fail with `Error::Timeout` if the response takes longer than `timeout`
+    /// or, if `timeout` is None, the function's default timeout.
+    pub fn process_req(&mut self, req: Value, timeout: Option<Duration>) -> Result<String, Error> {
         use prost::Message;
 
+        let deadline = timeout.or_else(|| self.function_config.timeout.map(Duration::from_millis))
+            .map(|timeout| Instant::now() + timeout);
+
         let sys_req = syscalls::Request {
             payload: req.to_string(),
         }
@@ -335,7 +456,7 @@ impl Vm {
 
         self.send_into_vm(sys_req)?;
 
-        self.process_syscalls()
+        self.process_syscalls(deadline)
     }
 
     /// Send a HTTP GET request no matter if an authentication token is present
@@ -383,13 +504,36 @@ impl Vm {
         }
     }
 
-    fn send_req(&self, invoke: syscalls::Invoke) -> bool {
+    /// Build the request an invoke syscall makes to another function, a child of the request
+    /// being processed. Fail if the payload is not JSON.
+    fn invoke_request(&self, invoke: &syscalls::Invoke, timeout: Option<Duration>) -> Result<Request, serde_json::Error> {
+        let mut ancestry = self.ancestry.clone();
+        ancestry.push(self.function_name.clone());
+        Ok(Request {
+            function: invoke.function.clone(),
+            payload: serde_json::from_str(invoke.payload.as_str())?,
+            timeout: timeout.map(|t| t.as_millis() as u64),
+            // the callee must not be able to leak what the caller has read
+            label: Some(self.current_label.clone()),
+            trace: self.trace.as_ref().map(TraceContext::child),
+            ancestry,
+            ..Default::default()
+        })
+    }
+
+    /// Submit a request to another function whose response is sent to `rsp_sender`.
+    /// Return false if the request was not accepted, in which case the reason, if any,
+    /// is sent to `rsp_sender` right away.
+    fn send_req(&self, invoke: syscalls::Invoke, timeout: Option<Duration>, rsp_sender: Sender<Response>) -> bool {
         use time::precise_time_ns;
         if let Some(invoke_handle) = self.handle.as_ref().and_then(|h| h.invoke_handle.as_ref()) {
-            let (tx, _) = mpsc::channel();
-            let req = Request {
-                function: invoke.function,
-                payload: serde_json::from_str(invoke.payload.as_str()).expect("json"),
+            let req = match self.invoke_request(&invoke, timeout) {
+                Ok(req) => req,
+                Err(e) => {
+                    debug!("Invalid invoke payload, ignoring invoke syscall. {:?}", e);
+                    let _ = rsp_sender.send(Response::new(RequestStatus::BadRequest(e.to_string())));
+                    return false;
+                }
             };
             use crate::metrics::RequestTimestamps;
             let timestamps = RequestTimestamps {
@@ -397,14 +541,52 @@ impl Vm {
                 request: req.clone(),
                 ..Default::default()
             };
-            invoke_handle.send(Message::Request((req, tx, timestamps))).is_ok()
+            invoke_handle.submit((req, rsp_sender, timestamps))
         } else {
             debug!("No invoke handle, ignoring invoke syscall. {:?}", invoke);
             false
         }
     }
 
-    fn process_syscalls(&mut self) -> Result<String, Error> {
+    /// Invoke another function and block until it responds. The callee must respond before
+    /// the caller's `deadline`, which it gets as its own, otherwise the caller times out. A
+    /// caller without a deadline waits until the callee responds, before its function's
+    /// `timeout` if it has one, so that it never leaves a callee running with nobody waiting
+    /// for its result.
+    fn send_req_sync(&mut self, invoke: syscalls::Invoke, deadline: Option<Instant>) -> Result<syscalls::InvokeSyncResponse, Error> {
+        let (tx, rx) = mpsc::channel();
+        let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        // a rejected request is still responded to, e.g. with `RequestStatus::Overloaded`
+        self.send_req(invoke, timeout, tx);
+        let response = match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(response) => Some(response),
+                Err(mpsc::RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                Err(mpsc::RecvTimeoutError::Disconnected) => None,
+            },
+            None => rx.recv().ok(),
+        };
+        if let Some(label) = response.as_ref().and_then(|r| r.label.as_ref()) {
+            // the callee's output may depend on everything it has read
+            self.taint_with_label(label);
+        }
+        Ok(match response {
+            Some(response) => syscalls::InvokeSyncResponse {
+                success: response.status == RequestStatus::SentToVM,
+                payload: if response.output.is_null() { String::new() } else { response.output.to_string() },
+                status: serde_json::to_string(&response.status).unwrap(),
+                error: response.error.map(|e| serde_json::to_string(&e).unwrap()),
+            },
+            None => syscalls::InvokeSyncResponse {
+                success: false,
+                payload: String::new(),
+                status: serde_json::to_string(&RequestStatus::Dropped).unwrap(),
+                error: None,
+            },
+        })
+    }
+
+    fn process_syscalls(&mut self, deadline: Option<Instant>) -> Result<String, Error> {
         use lmdb::{Transaction, WriteFlags};
         use prost::Message;
         use std::io::Read;
@@ -418,10 +600,22 @@ impl Vm {
             let buf = {
                 let mut lenbuf = [0;4];
                 let mut conn = &self.handle.as_ref().unwrap().conn;
-                conn.read_exact(&mut lenbuf).map_err(|e| Error::VsockRead(e))?;
+                // bound the wait for the next syscall by the time left until the deadline
+                let read_timeout = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(Error::Timeout);
+                        }
+                        Some(deadline - now)
+                    }
+                    None => None,
+                };
+                conn.set_read_timeout(read_timeout).map_err(|e| Error::VsockRead(e))?;
+                conn.read_exact(&mut lenbuf).map_err(vsock_read_error)?;
                 let size = u32::from_be_bytes(lenbuf);
                 let mut buf = vec![0u8; size as usize];
-                conn.read_exact(&mut buf).map_err(|e| Error::VsockRead(e))?;
+                conn.read_exact(&mut buf).map_err(vsock_read_error)?;
                 buf
             };
             match Syscall::decode(buf.as_ref()).map_err(|e| Error::Rpc(e))?.syscall {
@@ -429,7 +623,19 @@ impl Vm {
                     return Ok(r.payload);
                 }
                 Some(SC::Invoke(invoke)) => {
-                    let result = syscalls::InvokeResponse { success: self.send_req(invoke) };
+                    // nobody waits for the response, unless the request is rejected right away
+                    let (tx, rx) = mpsc::channel();
+                    let success = self.send_req(invoke, None, tx);
+                    let rejection = if success { None } else { rx.try_recv().ok() };
+                    let result = syscalls::InvokeResponse {
+                        success,
+                        status: rejection.as_ref().map(|r| serde_json::to_string(&r.status).unwrap()),
+                        error: rejection.and_then(|r| r.error).map(|e| serde_json::to_string(&e).unwrap()),
+                    };
+                    self.send_into_vm(result.encode_to_vec())?;
+                }
+                Some(SC::InvokeSync(invoke)) => {
+                    let result = self.send_req_sync(invoke, deadline)?;
                     self.send_into_vm(result.encode_to_vec())?;
                 }
                 Some(SC::ReadKey(rk)) => {
@@ -735,9 +941,59 @@ impl Vm {
 impl Drop for Vm {
     /// shutdown this vm
     fn drop(&mut self) {
-        let handle = self.handle.as_ref().unwrap();
-        if let Err(e) = handle.conn.shutdown(Shutdown::Both) {
-            error!("Failed to shut down unix connection: {:?}", e);
+        // a VM that failed to launch has no handle
+        if let Some(handle) = self.handle.as_ref() {
+            if let Err(e) = handle.conn.shutdown(Shutdown::Both) {
+                error!("Failed to shut down unix connection: {:?}", e);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callee_starts_at_caller_label() {
+        use crate::message::Message;
+        use crate::queue::{RequestQueue, DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT};
+        use std::collections::BTreeMap;
+
+        let queue = RequestQueue::new(DEFAULT_MAX_DEPTH, DEFAULT_STARVATION_LIMIT, &BTreeMap::new());
+        // what a worker does with the next request before the callee runs it
+        let serve = |callee: &mut Vm| match queue.recv() {
+            Message::Request((req, _, _)) => callee.set_label(req.label.as_ref()),
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        let mut callee = Vm::new(1, String::new(), "callee".to_string(), FunctionConfig::default(), false);
+        let fresh_label = DCLabel::new(true, [["callee"]]);
+        assert!(callee.current_label().can_flow_to(&fresh_label));
+
+        // a caller that has read user data invokes the callee
+        let mut caller = Vm::new(0, String::new(), "caller".to_string(), FunctionConfig::default(), false);
+        let caller_label = DCLabel::new([["user"]], [["caller"]]);
+        caller.taint_with_label(&caller_label);
+        let invoke = syscalls::Invoke { function: "callee".to_string(), payload: "{}".to_string() };
+        let (tx, _rx) = mpsc::channel();
+        assert!(queue.submit((caller.invoke_request(&invoke, None).unwrap(), tx.clone(), Default::default())));
+        serve(&mut callee);
+        assert!(caller_label.can_flow_to(callee.current_label()));
+        assert!(fresh_label.can_flow_to(callee.current_label()));
+        // so the callee cannot write the data to a public location
+        assert!(!callee.current_label().can_flow_to(&DCLabel::public()));
+
+        // the next request from a client to the warm VM starts afresh
+        let req = Request { function: "callee".to_string(), ..Default::default() };
+        assert!(queue.submit((req, tx, Default::default())));
+        serve(&mut callee);
+        assert!(callee.current_label().can_flow_to(&fresh_label));
+        assert!(fresh_label.can_flow_to(callee.current_label()));
+    }
+
+    #[test]
+    fn test_clients_cannot_set_label() {
+        let req: Request = serde_json::from_str(r#"{"function": "f", "payload": {}, "label": "bottom"}"#).unwrap();
+        assert!(req.label.is_none());
+    }
+}
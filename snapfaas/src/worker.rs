@@ -1,26 +1,39 @@
 //! Workers proxies requests and responses between the request manager and VMs.
 //! Each worker runs in its own thread and is modeled as the following state
 //! machine:
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::mpsc::Sender;
 use std::sync::mpsc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::os::unix::net::UnixListener;
+use std::time::Duration;
 
 use log::{error, debug};
 use time::precise_time_ns;
 
 use crate::message::Message;
-use crate::request::{RequestStatus, Response};
+use crate::queue::RequestQueue;
+use crate::request::{RequestError, RequestStatus, Response};
 use crate::vm;
 use crate::metrics;
 use crate::resource_manager;
+use crate::results;
+use crate::trace;
 
 // one hour
 const FLUSH_INTERVAL_SECS: u64 = 3600;
+/// one in this many workers of a pool is kept for requests invoked by functions
+pub const RESERVED_WORKERS_RATIO: usize = 8;
 
+/// Return how many workers of a pool of `pool_size` to keep for requests invoked by functions,
+/// at least one unless the pool has a single worker
+pub fn reserved_workers(pool_size: usize) -> usize {
+    if pool_size < 2 {
+        0
+    } else {
+        std::cmp::max(1, pool_size / RESERVED_WORKERS_RATIO)
+    }
+}
 
 #[derive(Debug)]
 pub struct Worker {
@@ -29,16 +42,35 @@ pub struct Worker {
 
 impl Worker {
     pub fn new(
-        receiver: Arc<Mutex<Receiver<Message>>>,
+        request_queue: RequestQueue,
+        vm_req_sender: Sender<Message>,
+        cid: u32,
+    ) -> Self {
+        Worker::spawn(request_queue, vm_req_sender, cid, false)
+    }
+
+    /// Create a worker that only serves requests invoked by functions, see `reserved_workers`
+    pub fn reserved(
+        request_queue: RequestQueue,
         vm_req_sender: Sender<Message>,
-        func_req_sender: Sender<Message>,
         cid: u32,
+    ) -> Self {
+        Worker::spawn(request_queue, vm_req_sender, cid, true)
+    }
+
+    fn spawn(
+        request_queue: RequestQueue,
+        vm_req_sender: Sender<Message>,
+        cid: u32,
+        invoked_only: bool,
     ) -> Self {
         let handle = thread::spawn(move || {
             let id = thread::current().id();
             std::fs::create_dir_all("./out").unwrap();
             let log_file = std::fs::File::create(format!("./out/thread-{:?}.stat", id)).unwrap();
+            let trace_file = std::fs::File::create(format!("./out/thread-{:?}.traces.json", id)).unwrap();
             let mut stat = metrics::WorkerMetrics::new(log_file);
+            stat.set_trace_file(trace_file);
             stat.start_timed_flush(FLUSH_INTERVAL_SECS);
 
             let vm_listener_path = format!("worker-{}.sock_1234", cid);
@@ -54,7 +86,11 @@ impl Worker {
                     Err(e) => panic!("Failed to clone unix listener \"worker-{}.sock_1234\": {:?}", cid, e),
                 };
 
-                let msg: Message = receiver.lock().unwrap().recv().unwrap();
+                let msg: Message = if invoked_only {
+                    request_queue.recv_invoked()
+                } else {
+                    request_queue.recv()
+                };
                 match msg {
                     // To shutdown, dump collected statistics and then terminate
                     Message::Shutdown => {
@@ -66,8 +102,20 @@ impl Worker {
                         debug!("processing request to function {}", &req.function);
                         
                         tsps.arrived = precise_time_ns();
+                        tsps.unix_offset = trace::unix_offset();
 
                         let function_name = req.function.clone();
+                        let async_id = req.async_id.clone();
+                        let trace = req.trace.clone();
+                        if req.time_left() == Some(Duration::ZERO) {
+                            error!("[Worker {:?}] Request to function {:?} expired in the queue", id, function_name);
+                            let mut response = Response::failed(RequestStatus::Timeout, RequestError::Timeout);
+                            response.timings = Some(tsps.timings());
+                            response.trace = trace;
+                            respond(&rsp_sender, async_id, response);
+                            stat.push(tsps);
+                            continue;
+                        }
                         let (tx, rx) = mpsc::channel();
                         vm_req_sender.send(Message::GetVm(function_name.clone(), tx)).expect("Failed to send GetVm request");
                         match rx.recv().expect("Failed to receive GetVm response") {
@@ -75,11 +123,12 @@ impl Worker {
                                 tsps.allocated = precise_time_ns();
                                 if !vm.is_launched() {
                                     // newly allocated VM is returned, launch it first
-                                    if let Err(e) = vm.launch(Some(func_req_sender.clone()), vm_listener_dup, cid, false, None) {
-                                        handle_vm_error(e);                                    
-                                        let _ = rsp_sender.send(Response {
-                                            status: RequestStatus::LaunchFailed,
-                                        });
+                                    tsps.cold_start = true;
+                                    if let Err(e) = vm.launch(Some(request_queue.clone()), vm_listener_dup, cid, false, None) {
+                                        let mut response = Response::failed(RequestStatus::LaunchFailed, handle_vm_error(e));
+                                        response.timings = Some(tsps.timings());
+                                        response.trace = trace;
+                                        respond(&rsp_sender, async_id, response);
                                         // a VM launched or not occupies system resources, we need
                                         // to put back the resources assigned to this VM.
                                         vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
@@ -92,25 +141,61 @@ impl Worker {
                                 debug!("VM is launched");
                                 tsps.launched = precise_time_ns();
 
-                                match vm.process_req(req.payload) {
+                                // only the time left after queueing, allocation and launch
+                                let timeout = req.time_left();
+                                if timeout == Some(Duration::ZERO) {
+                                    error!("[Worker {:?}] Request to function {:?} expired before it ran", id, function_name);
+                                    let mut response = Response::failed(RequestStatus::Timeout, RequestError::Timeout);
+                                    response.timings = Some(tsps.timings());
+                                    response.trace = trace;
+                                    respond(&rsp_sender, async_id, response);
+                                    // the VM has not run the request, it can serve another
+                                    vm_req_sender.send(Message::ReleaseVm(vm)).expect("Failed to send ReleaseVm request");
+                                    stat.push(tsps);
+                                    continue;
+                                }
+                                // each request starts afresh, at least at the label of its caller
+                                vm.set_label(req.label.as_ref());
+                                // requests the function invokes are children of this one
+                                vm.set_trace(trace.clone());
+                                vm.set_ancestry(req.ancestry);
+                                let cpu_time = vm.cpu_time();
+                                let result = vm.process_req(req.payload, timeout);
+                                tsps.usage = vm.usage_since(cpu_time);
+                                match result {
                                     Ok(rsp) => {
                                         tsps.completed = precise_time_ns();
-                                        // TODO: output are currently ignored
                                         debug!("{:?}", rsp);
-                                        let _ = rsp_sender.send(Response {
-                                            status: RequestStatus::SentToVM(rsp),
-                                        });
+                                        let mut response = Response::completed(&rsp);
+                                        response.timings = Some(tsps.timings());
+                                        response.label = Some(vm.current_label().clone());
+                                        response.trace = trace;
+                                        respond(&rsp_sender, async_id, response);
+                                        vm_req_sender.send(Message::ReleaseVm(vm)).expect("Failed to send ReleaseVm request");
+                                    }
+                                    Err(e) => {
+                                        let status = if let vm::Error::Timeout = e {
+                                            // the guest may never respond, stop it
+                                            vm.kill();
+                                            RequestStatus::Timeout
+                                        } else {
+                                            RequestStatus::ExecutionFailed
+                                        };
+                                        let mut response = Response::failed(status, handle_vm_error(e));
+                                        response.timings = Some(tsps.timings());
+                                        response.trace = trace;
+                                        respond(&rsp_sender, async_id, response);
+                                        // the VM may be left in a broken state, do not hand it out again
+                                        vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
                                     }
-                                    Err(e) => handle_vm_error(e),
                                 }
-
-                                vm_req_sender.send(Message::ReleaseVm(vm)).expect("Failed to send ReleaseVm request");
                             },
                             Err(e) => {
                                 let id = thread::current().id();
                                 let status = match e {
                                     resource_manager::Error::InsufficientEvict |
-                                    resource_manager::Error::LowMemory(_) => {
+                                    resource_manager::Error::LowMemory(_) |
+                                    resource_manager::Error::LowCpu(_) => {
                                         error!("[Worker {:?}] Resource exhaustion", id);
                                         RequestStatus::ResourceExhausted
                                     }
@@ -118,14 +203,23 @@ impl Worker {
                                         error!("[Worker {:?}] Requested function doesn't exist: {:?}", id, function_name);
                                         RequestStatus::FunctionNotExist
                                     }
+                                    resource_manager::Error::TenantQuota(ref tenant) => {
+                                        error!("[Worker {:?}] Tenant {:?} of function {:?} is at its quota", id, tenant, function_name);
+                                        RequestStatus::ResourceExhausted
+                                    }
+                                    resource_manager::Error::ConcurrencyLimit(limit) => {
+                                        error!("[Worker {:?}] Function {:?} is at its concurrency limit {}", id, function_name, limit);
+                                        RequestStatus::Overloaded
+                                    }
                                     _ => {
                                         error!("[Worker {:?}] Unexpected resource_manager error: {:?}", id, e);
                                         RequestStatus::Dropped
                                     }
                                 };
-                                let _ = rsp_sender.send(Response {
-                                    status,
-                                });
+                                let mut response = Response::failed(status, e);
+                                response.timings = Some(tsps.timings());
+                                response.trace = trace;
+                                respond(&rsp_sender, async_id, response);
                             }
                         }
 
@@ -147,13 +241,32 @@ impl Worker {
     }
 }
 
-fn handle_vm_error(vme: vm::Error) {
+/// Send the response back to the gateway or, for an asynchronous request,
+/// store it in the result store
+pub(crate) fn respond(rsp_sender: &Sender<Response>, async_id: Option<String>, response: Response) {
+    match async_id {
+        Some(id) => {
+            if let Err(e) = results::complete(&id, response) {
+                error!("[Worker {:?}] Failed to store result of request {}: {:?}", thread::current().id(), id, e);
+            }
+        }
+        None => {
+            let _ = rsp_sender.send(response);
+        }
+    }
+}
+
+/// Log a VM error and return it as the error to report to the client
+fn handle_vm_error(vme: vm::Error) -> RequestError {
     let id = thread::current().id();
     match vme {
         vm::Error::ProcessSpawn(_) | vm::Error::VsockListen(_) =>
             error!("[Worker {:?}] Failed to start vm due to: {:?}", id, vme),
         vm::Error::VsockRead(_) | vm::Error::VsockWrite(_) =>
             error!("[Worker {:?}] Vm failed to process request due to: {:?}", id, vme),
-        _ => (),
+        vm::Error::Timeout =>
+            error!("[Worker {:?}] Vm did not respond before the deadline, killing it", id),
+        _ => error!("[Worker {:?}] Vm error: {:?}", id, vme),
     }
+    vme.into()
 }
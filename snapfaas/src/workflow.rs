@@ -0,0 +1,604 @@
+//! Workflows compose functions
+//!
+//! A workflow is defined under `workflows` in the controller configuration file (YAML, or JSON
+//! which is valid YAML) next to `functions`, and is invoked like a function by its name.
+//! It is a sequence of steps, each step taking the previous step's output as its input:
+//!
+//! ```yaml
+//! workflows:
+//!   grade:
+//!     steps:
+//!       - name: fetch
+//!         function: fetch_repo
+//!         retries: 2
+//!       - name: checks
+//!         parallel:
+//!           - name: tests
+//!             function: run_tests
+//!             timeout: 60000
+//!           - name: lint
+//!             function: run_lint
+//!             on_failure: continue
+//!       # takes in {"tests": ..., "lint": ...}
+//!       - name: report
+//!         function: report
+//!       - name: maybe_notify
+//!         branch:
+//!           if: { path: "/passed", equals: false }
+//!           then:
+//!             name: notify
+//!             function: notify
+//! ```
+//!
+//! The controller runs each workflow invocation in its own thread and submits the function
+//! steps to the worker pool like any other request. Running workflows count against the
+//! request queue's depth: past it, invocations are responded to with `Overloaded`. The response carries the last step's
+//! output and the result of every function step that ran. A failed step fails the workflow
+//! unless it sets `on_failure: continue`, in which case its output is `null`.
+//!
+//! A workflow request's `timeout` bounds the whole workflow: each step gets at most the time
+//! left, and once it runs out, no step is retried and the workflow fails with `Timeout`.
+//! Steps run at least at the label of every step that responded before them, so that a step
+//! cannot leak what an earlier one has read, and the workflow responds with their join.
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+use labeled::dclabel::DCLabel;
+use labeled::Label;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::precise_time_ns;
+
+use crate::alias::Alias;
+use crate::configs::FunctionConfig;
+use crate::message::RequestInfo;
+use crate::metrics::RequestTimestamps;
+use crate::queue::RequestQueue;
+use crate::request::{Request, RequestError, RequestStatus, Response, Timings};
+use crate::trace::TraceContext;
+use crate::worker;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Workflow {
+    /// run one after another, each step's input is the previous step's output
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    /// unique within the workflow, identifies the step's result
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: StepKind,
+    /// number of times a failed function step is retried
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// maximum time in ms each invocation of a function step may take
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepKind {
+    /// invoke a function with the step's input
+    Function(String),
+    /// run steps one after another
+    Sequence(Vec<Step>),
+    /// run steps concurrently on the same input (fan-out). The output is an object from
+    /// each step's name to its output, which the next step takes in (fan-in).
+    Parallel(Vec<Step>),
+    /// run one of two steps depending on the input
+    Branch(Branch),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Branch {
+    #[serde(rename = "if")]
+    pub condition: Condition,
+    pub then: Box<Step>,
+    /// if absent and the condition does not hold, the input is passed through
+    #[serde(default, rename = "else")]
+    pub otherwise: Option<Box<Step>>,
+}
+
+/// Holds if the value at JSON pointer `path` in the input equals `equals`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Condition {
+    pub path: String,
+    pub equals: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// fail the workflow
+    Abort,
+    /// carry on with `null` as the step's output
+    Continue,
+}
+
+impl Default for OnFailure {
+    fn default() -> Self {
+        OnFailure::Abort
+    }
+}
+
+/// The outcome of a function step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+    pub name: String,
+    pub function: String,
+    pub status: RequestStatus,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub output: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RequestError>,
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+#[derive(Debug)]
+struct Failure {
+    status: RequestStatus,
+    error: Option<RequestError>,
+}
+
+/// State shared by the steps of a workflow invocation
+struct Run<'a, F> {
+    invoke: &'a F,
+    // join of the labels of the steps that responded so far
+    label: Mutex<Option<DCLabel>>,
+    deadline: Option<Instant>,
+}
+
+impl<'a, F> Run<'a, F> {
+    fn label(&self) -> Option<DCLabel> {
+        self.label.lock().unwrap().clone()
+    }
+
+    /// Raise the label of the next steps to at least `label`
+    fn taint(&self, label: Option<&DCLabel>) {
+        if let Some(label) = label {
+            let mut current = self.label.lock().unwrap();
+            *current = Some(match current.take() {
+                Some(current) => current.lub(label.clone()),
+                None => label.clone(),
+            });
+        }
+    }
+
+    /// Return the time left until the deadline, None if there is none
+    fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn expired(&self) -> bool {
+        self.time_left() == Some(Duration::ZERO)
+    }
+}
+
+impl Workflow {
+    /// Check that step names are unique and that every function step invokes one of `functions`
+    /// or of `aliases`, which the request queue routes to a function
+    pub fn validate(
+        &self,
+        functions: &BTreeMap<String, FunctionConfig>,
+        aliases: &BTreeMap<String, Alias>,
+    ) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut stack: Vec<&Step> = self.steps.iter().collect();
+        while let Some(step) = stack.pop() {
+            if !names.insert(step.name.as_str()) {
+                return Err(format!("duplicate step name {:?}", step.name));
+            }
+            match &step.kind {
+                StepKind::Function(function) => {
+                    if !functions.contains_key(function) && !aliases.contains_key(function) {
+                        return Err(format!("step {:?} invokes unknown function {:?}", step.name, function));
+                    }
+                }
+                StepKind::Sequence(steps) | StepKind::Parallel(steps) => stack.extend(steps),
+                StepKind::Branch(branch) => {
+                    stack.push(&branch.then);
+                    if let Some(otherwise) = branch.otherwise.as_ref() {
+                        stack.push(otherwise);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the workflow on `input`, invoking functions with `invoke`. The steps start at `label`
+    /// and must respond before `deadline`.
+    pub fn run<F>(&self, input: Value, label: Option<DCLabel>, deadline: Option<Instant>, invoke: &F) -> Response
+    where
+        F: Fn(Request) -> Response + Sync,
+    {
+        let run = Run { invoke, label: Mutex::new(label), deadline };
+        let mut results = Vec::new();
+        let mut response = match run_steps(&self.steps, input, &run, &mut results) {
+            Ok(output) => Response { output, ..Response::new(RequestStatus::SentToVM) },
+            Err(_) if run.expired() => Response::failed(RequestStatus::Timeout, RequestError::Timeout),
+            Err(Failure { status, error }) => Response { error, ..Response::new(status) },
+        };
+        response.steps = results;
+        response.label = run.label();
+        response
+    }
+}
+
+fn run_steps<F>(steps: &[Step], input: Value, run: &Run<F>, results: &mut Vec<StepResult>) -> Result<Value, Failure>
+where
+    F: Fn(Request) -> Response + Sync,
+{
+    steps.iter().try_fold(input, |input, step| run_step(step, input, run, results))
+}
+
+fn run_step<F>(step: &Step, input: Value, run: &Run<F>, results: &mut Vec<StepResult>) -> Result<Value, Failure>
+where
+    F: Fn(Request) -> Response + Sync,
+{
+    let res = match &step.kind {
+        StepKind::Function(function) => run_function(step, function, input, run, results),
+        StepKind::Sequence(steps) => run_steps(steps, input, run, results),
+        StepKind::Parallel(steps) => {
+            let outcomes: Vec<(Result<Value, Failure>, Vec<StepResult>)> = std::thread::scope(|s| {
+                let handles: Vec<_> = steps.iter().map(|step| {
+                    let input = input.clone();
+                    s.spawn(move || {
+                        let mut results = Vec::new();
+                        (run_step(step, input, run, &mut results), results)
+                    })
+                }).collect();
+                handles.into_iter().map(|h| h.join().expect("workflow step panicked")).collect()
+            });
+            let mut outputs = serde_json::Map::new();
+            let mut failure = None;
+            for (step, (res, mut step_results)) in steps.iter().zip(outcomes) {
+                results.append(&mut step_results);
+                match res {
+                    Ok(output) => {
+                        outputs.insert(step.name.clone(), output);
+                    }
+                    Err(e) => {
+                        failure.get_or_insert(e);
+                    }
+                }
+            }
+            match failure {
+                Some(e) => Err(e),
+                None => Ok(Value::Object(outputs)),
+            }
+        }
+        StepKind::Branch(branch) => {
+            if input.pointer(&branch.condition.path) == Some(&branch.condition.equals) {
+                run_step(&branch.then, input, run, results)
+            } else if let Some(otherwise) = branch.otherwise.as_ref() {
+                run_step(otherwise, input, run, results)
+            } else {
+                Ok(input)
+            }
+        }
+    };
+    match res {
+        // past the deadline, the workflow fails whatever the step's `on_failure`
+        Err(_) if step.on_failure == OnFailure::Continue && !run.expired() => Ok(Value::Null),
+        res => res,
+    }
+}
+
+fn run_function<F>(
+    step: &Step,
+    function: &str,
+    input: Value,
+    run: &Run<F>,
+    results: &mut Vec<StepResult>,
+) -> Result<Value, Failure>
+where
+    F: Fn(Request) -> Response + Sync,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let response = match run.time_left() {
+            Some(left) if left == Duration::ZERO => Response::failed(RequestStatus::Timeout, RequestError::Timeout),
+            left => (run.invoke)(Request {
+                function: function.to_string(),
+                payload: input.clone(),
+                // at most the time left to the workflow
+                timeout: match (step.timeout, left.map(|left| left.as_millis() as u64)) {
+                    (Some(timeout), Some(left)) => Some(std::cmp::min(timeout, left)),
+                    (timeout, left) => timeout.or(left),
+                },
+                label: run.label(),
+                ..Default::default()
+            }),
+        };
+        // the step's output may depend on everything it has read
+        run.taint(response.label.as_ref());
+        let completed = response.status == RequestStatus::SentToVM;
+        if completed || attempts > step.retries || run.expired() {
+            debug!("workflow step {:?} finished after {} attempt(s): {:?}", step.name, attempts, response.status);
+            results.push(StepResult {
+                name: step.name.clone(),
+                function: function.to_string(),
+                status: response.status.clone(),
+                output: response.output.clone(),
+                error: response.error.clone(),
+                attempts,
+                timings: response.timings,
+            });
+            return if completed {
+                Ok(response.output)
+            } else {
+                Err(Failure { status: response.status, error: response.error })
+            };
+        }
+    }
+}
+
+/// Runs workflows on top of the worker pool
+#[derive(Debug, Clone)]
+pub struct Engine {
+    workflows: Arc<RwLock<BTreeMap<String, Workflow>>>,
+    queue: RequestQueue,
+    // number of workflow invocations running, at most the queue's max depth
+    running: Arc<AtomicUsize>,
+}
+
+impl Engine {
+    pub fn new(workflows: BTreeMap<String, Workflow>, queue: RequestQueue) -> Self {
+        Engine {
+            workflows: Arc::new(RwLock::new(workflows)),
+            queue,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Replace the workflows, those already running finish as they were defined
+    pub fn set_workflows(&self, workflows: BTreeMap<String, Workflow>) {
+        *self.workflows.write().unwrap() = workflows;
+    }
+
+    /// Run the workflow a request names in its own thread, or queue the request
+    /// if it names a function. Return false if the request was not accepted, e.g.,
+    /// because as many workflows as the queue holds requests are running.
+    pub fn submit(&self, info: RequestInfo) -> bool {
+        let workflow = self.workflows.read().unwrap().get(&info.0.function).cloned();
+        let workflow = match workflow {
+            Some(workflow) => workflow,
+            None => return self.queue.submit(info),
+        };
+        let (req, rsp_sender, _) = info;
+        // like the queue for functions, the workflow's timeout counts from admission
+        let deadline = req.deadline.or_else(|| req.timeout.map(|ms| Instant::now() + Duration::from_millis(ms)));
+        if self.running.fetch_add(1, Ordering::SeqCst) >= self.queue.max_depth() {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            warn!("Too many workflows running, rejecting workflow {:?}", req.function);
+            let response = Response { trace: req.trace, ..Response::new(RequestStatus::Overloaded) };
+            worker::respond(&rsp_sender, req.async_id, response);
+            return false;
+        }
+        let queue = self.queue.clone();
+        let running = self.running.clone();
+        std::thread::spawn(move || {
+            info!("Running workflow {:?}", req.function);
+            // every step inherits the workflow request's priority and is traced as its child
+            let priority = req.priority;
+            let trace = TraceContext::or_root(req.trace);
+            let mut response = workflow.run(req.payload, req.label, deadline, &|mut step_req: Request| {
+                step_req.priority = priority;
+                step_req.trace = Some(trace.child());
+                invoke(&queue, step_req)
+            });
+            response.trace = Some(trace);
+            info!("Workflow {:?} finished: {:?}", req.function, response.status);
+            worker::respond(&rsp_sender, req.async_id, response);
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        true
+    }
+}
+
+/// Submit a request to the worker pool and wait for its response
+fn invoke(queue: &RequestQueue, req: Request) -> Response {
+    let (tx, rx) = channel();
+    let timestamps = RequestTimestamps {
+        at_gateway: precise_time_ns(),
+        request: req.clone(),
+        ..Default::default()
+    };
+    queue.submit((req, tx, timestamps));
+    rx.recv().unwrap_or_else(|_| Response::new(RequestStatus::Dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const DEFINITION: &str = r#"
+steps:
+  - name: fetch
+    function: flaky
+    retries: 1
+  - name: checks
+    parallel:
+      - name: double
+        function: double
+      - name: lint
+        function: fail
+        on_failure: continue
+  - name: maybe_fail
+    branch:
+      if: { path: "/double", equals: 4 }
+      then:
+        name: report
+        function: echo
+      else:
+        name: abort
+        function: fail
+"#;
+
+    fn functions() -> BTreeMap<String, FunctionConfig> {
+        ["flaky", "double", "fail", "echo"].iter()
+            .map(|f| (f.to_string(), FunctionConfig::default()))
+            .collect()
+    }
+
+    #[test]
+    fn test_workflow_run() {
+        let workflow: Workflow = serde_yaml::from_str(DEFINITION).unwrap();
+        assert!(workflow.validate(&functions(), &BTreeMap::new()).is_ok());
+
+        let flaky_calls = AtomicUsize::new(0);
+        let invoke = |req: Request| -> Response {
+            match req.function.as_str() {
+                // fails on the first attempt
+                "flaky" if flaky_calls.fetch_add(1, Ordering::SeqCst) == 0 =>
+                    Response::new(RequestStatus::ExecutionFailed),
+                "flaky" | "echo" => Response::completed(&req.payload.to_string()),
+                "double" => Response::completed(&(req.payload.as_i64().unwrap() * 2).to_string()),
+                _ => Response::failed(RequestStatus::LaunchFailed, RequestError::KernelNotExist),
+            }
+        };
+
+        let response = workflow.run(serde_json::json!(2), None, None, &invoke);
+        assert_eq!(response.status, RequestStatus::SentToVM);
+        assert_eq!(response.output, serde_json::json!({ "double": 4, "lint": null }));
+        let steps: Vec<(&str, u32)> = response.steps.iter().map(|s| (s.name.as_str(), s.attempts)).collect();
+        assert_eq!(steps, vec![("fetch", 2), ("double", 1), ("lint", 1), ("report", 1)]);
+        assert_eq!(response.steps[2].status, RequestStatus::LaunchFailed);
+
+        // the else branch fails the workflow
+        let response = workflow.run(serde_json::json!(3), None, None, &invoke);
+        assert_eq!(response.status, RequestStatus::LaunchFailed);
+        assert_eq!(response.error, Some(RequestError::KernelNotExist));
+        assert_eq!(response.steps.last().unwrap().name, "abort");
+    }
+
+    #[test]
+    fn test_workflow_validate() {
+        let workflow: Workflow = serde_yaml::from_str(DEFINITION).unwrap();
+        let mut functions = functions();
+        functions.remove("echo");
+        assert!(workflow.validate(&functions, &BTreeMap::new()).is_err());
+        // a step may name an alias instead
+        let mut aliases = BTreeMap::new();
+        aliases.insert("echo".to_string(), serde_yaml::from_str("{echo@v1: 1}").unwrap());
+        assert!(workflow.validate(&functions, &aliases).is_ok());
+
+        let workflow: Workflow = serde_json::from_str(
+            r#"{"steps": [{"name": "a", "function": "echo"}, {"name": "a", "function": "echo"}]}"#,
+        ).unwrap();
+        assert!(workflow.validate(&self::functions(), &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_workflow_label() {
+        let workflow: Workflow = serde_yaml::from_str(r#"
+steps:
+  - name: read
+    function: read_secret
+  - name: publish
+    function: publish
+"#).unwrap();
+        let secret = DCLabel::new([["user"]], [["read_secret"]]);
+        let published_at = Mutex::new(None);
+        let invoke = |req: Request| -> Response {
+            match req.function.as_str() {
+                // reads user data, which raises its label
+                "read_secret" => Response {
+                    label: Some(req.label.unwrap_or_else(DCLabel::public).lub(secret.clone())),
+                    ..Response::completed("\"secret\"")
+                },
+                _ => {
+                    *published_at.lock().unwrap() = req.label.clone();
+                    Response { label: req.label, ..Response::completed(&req.payload.to_string()) }
+                }
+            }
+        };
+
+        let response = workflow.run(Value::Null, None, None, &invoke);
+        assert_eq!(response.status, RequestStatus::SentToVM);
+        // the step taking in the secret runs at least at its label
+        let published_at = published_at.lock().unwrap().clone().unwrap();
+        assert!(secret.can_flow_to(&published_at));
+        assert!(!published_at.can_flow_to(&DCLabel::public()));
+        assert!(secret.can_flow_to(response.label.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_workflow_deadline() {
+        let workflow: Workflow = serde_yaml::from_str(r#"
+steps:
+  - name: slow
+    function: slow
+    retries: 5
+    timeout: 60000
+    on_failure: continue
+  - name: echo
+    function: echo
+"#).unwrap();
+        let calls = AtomicUsize::new(0);
+        let invoke = |req: Request| -> Response {
+            calls.fetch_add(1, Ordering::SeqCst);
+            match req.function.as_str() {
+                "slow" => {
+                    // gets the workflow's time left rather than the step's timeout
+                    assert!(req.timeout.unwrap() <= 50);
+                    std::thread::sleep(Duration::from_millis(req.timeout.unwrap() + 5));
+                    Response::new(RequestStatus::Timeout)
+                }
+                _ => Response::completed(&req.payload.to_string()),
+            }
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let response = workflow.run(Value::Null, None, Some(deadline), &invoke);
+        assert_eq!(response.status, RequestStatus::Timeout);
+        // neither retried nor carried on with past the deadline
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(response.steps.len(), 1);
+    }
+
+    #[test]
+    fn test_engine_overload() {
+        use crate::message::Message;
+
+        let mut workflows = BTreeMap::new();
+        workflows.insert("grade".to_string(), serde_yaml::from_str(DEFINITION).unwrap());
+        // no worker serves the queue, the first workflow waits for its first step
+        let queue = RequestQueue::new(1, crate::queue::DEFAULT_STARVATION_LIMIT, &functions());
+        let engine = Engine::new(workflows, queue.clone());
+        let submit = || {
+            let (tx, rx) = channel();
+            let req = Request { function: "grade".to_string(), ..Default::default() };
+            let accepted = engine.submit((req, tx, RequestTimestamps::default()));
+            (accepted, rx)
+        };
+        let (accepted, running) = submit();
+        assert!(accepted);
+        let (accepted, rx) = submit();
+        assert!(!accepted);
+        assert_eq!(rx.recv().unwrap().status, RequestStatus::Overloaded);
+
+        // drop both attempts of the first step, so that the workflow fails and its thread exits
+        for _ in 0..2 {
+            match queue.recv() {
+                Message::Request((req, _, _)) => assert_eq!(req.function, "flaky"),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+        assert_eq!(running.recv().unwrap().status, RequestStatus::Dropped);
+    }
+}